@@ -1,4 +1,6 @@
+use crate::ping::MAXIMUM_PAYLOAD_SIZE;
 use clap::Clap;
+use std::str::FromStr;
 
 #[derive(Clap)]
 #[clap(
@@ -9,7 +11,7 @@ It mimics the interface of original ping."
 )]
 pub struct Opts {
     /// Setting of the IP Time to Live.
-    #[clap(short = "t")]
+    #[clap(short = "t", parse(try_from_str = parse_ttl))]
     pub ttl: Option<u32>,
     /// Time to wait for a response, in seconds.
     #[clap(short = "W", name="timeout")]
@@ -17,13 +19,508 @@ pub struct Opts {
     /// Stop after sending count ECHO_REQUEST packets.
     #[clap(short = "c", name="count")]
     pub count_packets: Option<usize>,
+    /// Stop once the bytes of payload are sent, K, M and G suffixes multiply by 1024.
+    #[clap(long = "total-bytes", name = "bytes", parse(try_from_str = parse_bytes))]
+    pub total_bytes: Option<usize>,
     /// Wait interval seconds between sending each packet. The default value is 1 second.
     #[clap(short = "i", name="interval")]
     pub send_interval: Option<f32>,
+    /// Write sent and received packets to the file in pcap format.
+    #[clap(long = "write", name = "file")]
+    pub pcap: Option<String>,
+    /// Write the statistics to the file in the Prometheus text format at the end.
+    #[clap(long = "prometheus", name = "metrics-file")]
+    pub prometheus: Option<String>,
+    /// Send the ICMP requests read from the pcap file instead of echo requests.
+    #[clap(long = "replay", name = "capture")]
+    pub replay: Option<String>,
+    /// Print the average rtt without the pct percent of the fastest
+    /// and the pct percent of the slowest replies next to the average.
+    #[clap(long = "trimmed-mean", name = "trim-pct", parse(try_from_str = parse_trim))]
+    pub trimmed_mean: Option<f64>,
+    /// Print the smoothed rtt and its variation the way TCP estimates them.
+    #[clap(long)]
+    pub srtt: bool,
+    /// Retry the name resolution up to n times with a growing delay if it fails.
+    #[clap(long = "dns-retries", name = "n-dns-retries", default_value = "0")]
+    pub dns_retries: usize,
+    /// Try each resolved address in turn until one replies, giving up after ms milliseconds.
+    #[clap(long = "failover", name = "deadline-ms")]
+    pub failover: Option<u64>,
+    /// Resolve the name again every secs seconds and switch to a new address
+    /// if the current one isn't among the resolved ones anymore.
+    #[clap(long = "reresolve", name = "secs")]
+    pub reresolve: Option<u64>,
+    /// Randomize the interval by up to the fraction of it, e.g. 0.1 for ±10%.
+    #[clap(long = "interval-jitter", name = "fraction", parse(try_from_str = parse_jitter))]
+    pub interval_jitter: Option<f32>,
+    /// Wait up to ms milliseconds for late replies after the last request of a counted run.
+    #[clap(long = "grace", name = "ms")]
+    pub grace: Option<u64>,
+    /// Send no more than pps requests per second however they're scheduled.
+    /// Note that replies to a burst are read once all of its requests are sent
+    /// so a low rate inflates rtt of the burst.
+    #[clap(long = "rate", name = "pps", parse(try_from_str = parse_rate))]
+    pub rate: Option<f64>,
+    /// Send n packets back to back on each interval.
+    #[clap(long = "burst", name = "n")]
+    pub burst: Option<usize>,
+    /// Ping the other address along with the target and print the difference of rtt.
+    #[clap(long = "compare", name = "other-address", conflicts_with = "jsonl")]
+    pub compare: Option<String>,
+    /// Send -c requests (3 by default) of each payload size from start to end
+    /// and print the loss per size to find where it begins.
+    #[clap(long = "size-sweep", name = "start:end:step", parse(try_from_str = parse_size_sweep))]
+    pub size_sweep: Option<SizeSweep>,
+    /// Grow the payload by step bytes after each request until it reaches max bytes.
+    /// It can't be combined with a size sweep or information requests
+    /// since they set the payload size themselves.
+    #[clap(
+        long = "grow",
+        name = "step:max",
+        parse(try_from_str = parse_grow),
+        conflicts_with_all = &["start:end:step", "info-request"]
+    )]
+    pub grow: Option<(usize, usize)>,
+    /// Use the content of the file as the payload of echo requests.
+    #[clap(long = "payload-file", name = "path")]
+    pub payload_file: Option<String>,
+    /// Resend a request up to n times if no reply arrives in time.
+    /// The timeout is shared between the attempts.
+    #[clap(long = "retries", name = "n-retries", default_value = "0")]
+    pub retries: usize,
+    /// Discard replies larger than the bytes including the ip header.
+    #[clap(long = "max-reply", name = "reply-bytes")]
+    pub max_reply: Option<usize>,
+    /// Set the socket receive buffer size in bytes.
+    #[clap(long = "rcvbuf", name = "rcvbuf-bytes")]
+    pub recv_buffer_size: Option<usize>,
+    /// Set the socket send buffer size in bytes.
+    #[clap(long = "sndbuf", name = "sndbuf-bytes")]
+    pub send_buffer_size: Option<usize>,
+    /// Print rtt in the fixed unit instead of an automatically chosen one.
+    #[clap(long = "unit", possible_values = &["ms", "us"])]
+    pub unit: Option<Unit>,
+    /// Set the sequence number of the first request.
+    #[clap(long = "start-seq", name = "seq", default_value = "1")]
+    pub start_seq: u16,
+    /// Set the code of echo requests.
+    /// Note that some hosts drop echo requests with a nonstandard code.
+    #[clap(long = "code", name = "icmp-code", default_value = "0")]
+    pub code: u8,
+    /// Send ICMP information requests instead of echo requests.
+    /// The type is deprecated but some legacy devices still answer it.
+    #[clap(long = "info-request")]
+    pub info_request: bool,
+    /// Write a timestamp into the first 8 bytes of the payload
+    /// and show the rtt computed from the echoed one.
+    #[clap(long = "timestamp-payload")]
+    pub timestamp_payload: bool,
+    /// Leave the checksum of echo requests zero so the kernel can fill it in.
+    /// Note that raw sockets send such requests as they are.
+    #[clap(long = "skip-checksum")]
+    pub skip_checksum: bool,
+    /// Tag replies slower than ms milliseconds and don't count them as received.
+    #[clap(long = "max-rtt", name = "max-ms")]
+    pub max_rtt: Option<u64>,
+    /// Count replies slower than --max-rtt as received, they're still tagged.
+    #[clap(long = "slow-as-received")]
+    pub slow_as_received: bool,
+    /// Tag replies which rtt exceeds the minimum rtt by the factor.
+    #[clap(long = "spike-threshold", name = "factor", parse(try_from_str = parse_spike_threshold))]
+    pub spike_threshold: Option<f32>,
+    /// Print a line only when the host goes down or up again,
+    /// it's down since the first request without a reply.
+    #[clap(long = "changes-only")]
+    pub changes_only: bool,
+    /// Print a single progress line redrawn on each reply instead of a line per reply.
+    #[clap(long)]
+    pub progress: bool,
+    /// Allow pinging a broadcast address and print every host which replies.
+    #[clap(long)]
+    pub broadcast: bool,
+    /// Print every host which answered and the types of messages it sent after the statistics.
+    #[clap(long)]
+    pub audit: bool,
+    /// Show the number of requests left to send on each reply, it requires -c.
+    #[clap(long = "show-remaining")]
+    pub show_remaining: bool,
+    /// Exit with status 1 unless an echo reply is received,
+    /// error messages don't count as a reply.
+    #[clap(long = "require-echo")]
+    pub require_echo: bool,
+    /// Exit with status 1 if the loss is pct percent or more.
+    /// It's checked after --require-echo.
+    #[clap(long = "max-loss", name = "pct")]
+    pub max_loss: Option<f64>,
+    /// Exit with status 1 if the average rtt is ms milliseconds or more
+    /// or there's no rtt at all. It's checked after --require-echo.
+    #[clap(long = "max-avg-rtt", name = "avg-ms")]
+    pub max_avg_rtt: Option<u64>,
+    /// Show the size of the ICMP payload of each reply.
+    #[clap(long = "show-payload-len")]
+    pub show_payload_len: bool,
+    /// Show the numeric ICMP type and code next to the name of non-echo messages.
+    #[clap(long = "show-type-code")]
+    pub show_type_code: bool,
+    /// Show the ICMP identifier of each reply.
+    #[clap(long = "show-ident")]
+    pub show_ident: bool,
+    /// Match echo replies by the payload or by the identifier alone
+    /// for middleboxes which rewrite or strip the payload.
+    #[clap(
+        long = "match",
+        name = "matching",
+        default_value = "payload",
+        possible_values = &["payload", "ident"]
+    )]
+    pub matching: Matching,
+    /// Accept time exceeded messages only if they quote the whole payload
+    /// as rfc-792 requires, by default a truncated quote is accepted.
+    #[clap(long = "strict-rfc")]
+    pub strict_rfc: bool,
+    /// Tag replies which are addressed not to the source address
+    /// the kernel chooses for the target, e.g. on multi-homed hosts.
+    #[clap(long = "strict-destination")]
+    pub strict_destination: bool,
+    /// Print the number of hops on the return path estimated by the TTL of replies.
+    #[clap(long)]
+    pub hops: bool,
+    /// Print a line per reply or a dot per request without a reply like ping -f does.
+    /// Dots are printed by default if the interval is less than 50ms.
+    #[clap(long = "output", name = "style", possible_values = &["lines", "dots"])]
+    pub output: Option<Output>,
+    /// Collapse repeated identical errors into a single line with a counter.
+    #[clap(long = "quiet-errors")]
+    pub quiet_errors: bool,
+    /// Pad the fields of reply lines so they line up in columns.
+    #[clap(long)]
+    pub align: bool,
+    /// Print a JSON object per reply and a summary one, each on a separate line.
+    #[clap(long)]
+    pub jsonl: bool,
+    /// Color replies, errors and lost requests, auto colors only a terminal.
+    #[clap(
+        long = "color",
+        name = "when",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"]
+    )]
+    pub color: Color,
+    /// Don't print the "PING ... bytes of data" line at the start.
+    #[clap(long = "no-header")]
+    pub no_header: bool,
+    /// Copy the output to the file.
+    #[clap(long = "log", name = "log-file")]
+    pub log: Option<String>,
+    /// Prefix each line with the label in brackets or add it as a field in JSON.
+    #[clap(long = "label", name = "text")]
+    pub label: Option<String>,
+    /// Print timestamp before each line.
+    #[clap(short = "D")]
+    pub timestamp: bool,
+    /// The format of timestamps printed by -D.
+    #[clap(
+        long = "timestamp-format",
+        name = "format",
+        default_value = "unix",
+        possible_values = &["iso", "unix", "epoch-ms"]
+    )]
+    pub timestamp_format: TimestampFormat,
+    /// Verbose output.
+    #[clap(short = "v")]
+    pub verbose: bool,
+    /// Check that packets are built and parsed correctly without sending anything.
+    /// It exits with status 1 if a check fails.
+    #[clap(long)]
+    pub selftest: bool,
     /// The address ping which
-    pub address: String,
+    #[clap(required_unless_present = "selftest")]
+    pub address: Option<String>,
+}
+
+impl Opts {
+    /// Returns the address to ping, it's always set unless --selftest is.
+    pub fn target(&self) -> &str {
+        self.address.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Unit {
+    Milliseconds,
+    Microseconds,
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ms" => Ok(Unit::Milliseconds),
+            "us" => Ok(Unit::Microseconds),
+            _ => Err(format!("unknown unit {}", s)),
+        }
+    }
+}
+
+/// The payload sizes of a sweep.
+#[derive(Clone, Copy)]
+pub struct SizeSweep {
+    pub start: usize,
+    pub end: usize,
+    pub step: usize,
+}
+
+fn parse_size_sweep(s: &str) -> Result<SizeSweep, String> {
+    let parts = s
+        .split(':')
+        .map(|part| part.parse::<usize>().map_err(|err| err.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sweep = match parts.as_slice() {
+        [start, end, step] => SizeSweep {
+            start: *start,
+            end: *end,
+            step: *step,
+        },
+        _ => return Err(String::from("the sweep must be start:end:step")),
+    };
+
+    if sweep.step == 0 || sweep.start > sweep.end {
+        Err(String::from("the start exceeds the end or the step is 0"))
+    } else if sweep.end > MAXIMUM_PAYLOAD_SIZE {
+        Err(format!("the size is limited by {}", MAXIMUM_PAYLOAD_SIZE))
+    } else {
+        Ok(sweep)
+    }
+}
+
+fn parse_grow(s: &str) -> Result<(usize, usize), String> {
+    let mut parts = s.splitn(2, ':');
+    let step = parts.next().unwrap_or_default();
+    let max = parts.next().ok_or("the growth must be step:max")?;
+    let step = step.parse::<usize>().map_err(|err| err.to_string())?;
+    let max = max.parse::<usize>().map_err(|err| err.to_string())?;
+
+    if step == 0 {
+        Err(String::from("the step is 0"))
+    } else if max > MAXIMUM_PAYLOAD_SIZE {
+        Err(format!("the size is limited by {}", MAXIMUM_PAYLOAD_SIZE))
+    } else {
+        Ok((step, max))
+    }
+}
+
+fn parse_bytes(s: &str) -> Result<usize, String> {
+    let (number, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+
+    let number = number.parse::<usize>().map_err(|err| err.to_string())?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| String::from("the number of bytes is too large"))
+}
+
+fn parse_trim(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(pct) if (0.0..50.0).contains(&pct) => Ok(pct),
+        Ok(..) => Err(String::from("the trimmed percent must be in range 0..50")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+        Ok(..) => Err(String::from("the rate must be a positive number")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_spike_threshold(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(factor) if factor.is_finite() && factor >= 1.0 => Ok(factor),
+        Ok(..) => Err(String::from("the factor must be a number not less than 1")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_jitter(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        Ok(..) => Err(String::from("the fraction must be in range 0..=1")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn parse_ttl(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(ttl) if (1..=255).contains(&ttl) => Ok(ttl),
+        Ok(..) => Err(String::from("ttl must be in range 1..=255")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Output {
+    Lines,
+    Dots,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(Output::Lines),
+            "dots" => Ok(Output::Dots),
+            _ => Err(format!("unknown output style {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Matching {
+    Payload,
+    Ident,
+}
+
+impl FromStr for Matching {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "payload" => Ok(Matching::Payload),
+            "ident" => Ok(Matching::Ident),
+            _ => Err(format!("unknown matching {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(format!("unknown color mode {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum TimestampFormat {
+    /// ISO 8601 date and time in UTC.
+    Iso,
+    /// Seconds since the epoch with microseconds.
+    Unix,
+    /// Milliseconds since the epoch.
+    EpochMs,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iso" => Ok(TimestampFormat::Iso),
+            "unix" => Ok(TimestampFormat::Unix),
+            "epoch-ms" => Ok(TimestampFormat::EpochMs),
+            _ => Err(format!("unknown timestamp format {}", s)),
+        }
+    }
 }
 
 pub fn config() -> Opts {
     Opts::parse()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttl_range() {
+        assert_eq!(parse_ttl("1"), Ok(1));
+        assert_eq!(parse_ttl("255"), Ok(255));
+        assert!(parse_ttl("0").is_err());
+        assert!(parse_ttl("256").is_err());
+        assert!(parse_ttl("-1").is_err());
+    }
+
+    #[test]
+    fn spike_threshold_is_finite() {
+        assert_eq!(parse_spike_threshold("1"), Ok(1.0));
+        assert_eq!(parse_spike_threshold("2.5"), Ok(2.5));
+        assert!(parse_spike_threshold("0.5").is_err());
+        assert!(parse_spike_threshold("NaN").is_err());
+        assert!(parse_spike_threshold("inf").is_err());
+    }
+
+    #[test]
+    fn jitter_range() {
+        assert_eq!(parse_jitter("0"), Ok(0.0));
+        assert_eq!(parse_jitter("1"), Ok(1.0));
+        assert!(parse_jitter("-0.1").is_err());
+        assert!(parse_jitter("1.1").is_err());
+        assert!(parse_jitter("NaN").is_err());
+    }
+
+    #[test]
+    fn rate_is_positive() {
+        assert_eq!(parse_rate("0.5"), Ok(0.5));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-1").is_err());
+        assert!(parse_rate("inf").is_err());
+        assert!(parse_rate("NaN").is_err());
+    }
+
+    #[test]
+    fn trimmed_percent_range() {
+        assert_eq!(parse_trim("0"), Ok(0.0));
+        assert_eq!(parse_trim("10"), Ok(10.0));
+        assert!(parse_trim("50").is_err());
+        assert!(parse_trim("NaN").is_err());
+    }
+
+    #[test]
+    fn bytes_with_suffix() {
+        assert_eq!(parse_bytes("100"), Ok(100));
+        assert_eq!(parse_bytes("2K"), Ok(2048));
+        assert_eq!(parse_bytes("1m"), Ok(1 << 20));
+        assert!(parse_bytes("K").is_err());
+        assert!(parse_bytes(&format!("{}G", usize::MAX)).is_err());
+    }
+
+    #[test]
+    fn grow_step_and_max() {
+        assert_eq!(parse_grow("8:64"), Ok((8, 64)));
+        assert!(parse_grow("8").is_err());
+        assert!(parse_grow("0:64").is_err());
+        assert!(parse_grow(&format!("8:{}", MAXIMUM_PAYLOAD_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn size_sweep_bounds() {
+        let sweep = parse_size_sweep("8:64:8").unwrap();
+        assert_eq!((sweep.start, sweep.end, sweep.step), (8, 64, 8));
+
+        assert!(parse_size_sweep("8:64").is_err());
+        assert!(parse_size_sweep("64:8:8").is_err());
+        assert!(parse_size_sweep("8:64:0").is_err());
+        assert!(parse_size_sweep(&format!("8:{}:8", MAXIMUM_PAYLOAD_SIZE + 1)).is_err());
+    }
+}
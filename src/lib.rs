@@ -1,3 +1,5 @@
 pub mod args;
 pub mod packet;
+pub mod pcap;
 pub mod ping;
+pub mod stats;
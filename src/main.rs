@@ -1,32 +1,158 @@
 use niping::{
-    args,
-    packet::icmp::PacketType,
-    ping::{self, PacketInfo, PingError, Socket2, DATA_SIZE},
+    args::{self, Color, Matching, Output, SizeSweep, TimestampFormat, Unit},
+    packet::{
+        icmp::{self, IcmpBuilder, IcmpPacket, PacketType},
+        ip::{self, IPV4Builder, IPV4Packet},
+        Builder, Packet,
+    },
+    pcap,
+    ping::{self, PacketInfo, PingError, PingEvent, Socket2, MAXIMUM_PAYLOAD_SIZE},
+    stats::RttStats,
 };
 use std::{
-    io,
-    net::IpAddr,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    future::Future,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
-    time::{self, Duration},
+    task::{Context, Poll},
+    time::{self, Duration, SystemTime, UNIX_EPOCH},
 };
 use trust_dns_resolver::config::*;
+use trust_dns_resolver::error::ResolveErrorKind;
 use trust_dns_resolver::Resolver;
 
 const DEFAULT_SEND_INTERVAL: Duration = Duration::from_secs(1);
 const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// The number of send and receive errors in a row after which the socket is reopened.
+const REOPEN_AFTER: usize = 3;
+/// The number of requests of each size sent by a size sweep by default.
+const SWEEP_PROBES: usize = 3;
+/// The interval below which the dots are printed instead of lines by default.
+const DOTS_INTERVAL: Duration = Duration::from_millis(50);
+/// The number of the last lost requests kept to detect a cadence of losses.
+const LOST_WINDOW: usize = 16;
+/// ANSI colors of replies, error messages and failed requests.
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const RED: &str = "31";
+/// The number of rtt samples kept to compute the trimmed mean.
+const TRIMMED_MEAN_SAMPLES: usize = 10_000;
+/// The interval of requests which check whether the network is up again.
+const NETWORK_DOWN_INTERVAL: Duration = Duration::from_secs(1);
+/// The width the name and address of a responder are padded to by --align,
+/// longer ones are left as they are.
+const ALIGNED_SOURCE_WIDTH: usize = 40;
+/// The delay before the first retry of a failed name resolution, it's doubled on each retry.
+const DNS_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Formats a line and prints it by the printer.
+macro_rules! output {
+    ($out:expr, $($arg:tt)*) => {
+        $out.line(&format!($($arg)*))
+    };
+}
+
+/// Printer prints the output lines marked with the label and copies them to the log file.
+#[derive(Default)]
+struct Printer {
+    /// The label output lines are marked with, it's set by --label.
+    label: Option<String>,
+    /// The file the output is copied to, it's set by --log.
+    log: Option<fs::File>,
+}
+
+impl Printer {
+    /// Prints a line and copies it to the log file.
+    ///
+    /// The line is flushed so a reader of a pipe gets it as soon as it's printed.
+    fn line(&mut self, line: &str) {
+        let line = self.labeled(line);
+        println!("{}", line);
+        let _ = io::stdout().flush();
+        self.log(&line);
+    }
+
+    /// Prefixes the line with the label or adds the label to a JSON object.
+    fn labeled(&self, line: &str) -> String {
+        match self.label.as_deref() {
+            Some(label) if line.starts_with('{') => {
+                format!(r#"{{"label":{},{}"#, json_string(label), &line[1..])
+            }
+            // there's nothing to mark in a separating line
+            Some(label) if !line.is_empty() => format!("[{}] {}", label, line),
+            _ => line.to_string(),
+        }
+    }
+
+    /// Appends the line to the log file if it's set.
+    ///
+    /// The file is written on each line so nothing is lost if niping is killed.
+    fn log(&mut self, line: &str) {
+        if let Some(file) = self.log.as_mut() {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
 
 fn main() {
     let opts = args::config();
-    let address = match parse_address(&opts.address) {
-        Some(addr) => addr,
-        None => {
-            println!("PING: {}: Name or service not known", opts.address);
+    let mut out = Printer {
+        label: opts.label.clone(),
+        ..Printer::default()
+    };
+    if opts.selftest {
+        match selftest() {
+            Ok(()) => output!(out, "selftest passed"),
+            Err(err) => {
+                output!(out, "PING: selftest: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(path) = opts.log.as_ref() {
+        match fs::File::create(path) {
+            Ok(file) => out.log = Some(file),
+            Err(err) => {
+                output!(out, "PING: {}: {}", path, io_error_to_string(err));
+                return;
+            }
+        }
+    }
+
+    let resolve = || parse_address(opts.target());
+    let addresses = match retry_resolution(opts.dns_retries, DNS_BACKOFF, resolve) {
+        Ok(addresses) => addresses,
+        Err(AddressError::Unknown) if opts.dns_retries > 0 => {
+            output!(
+                out,
+                "PING: {}: Name or service not known after {} attempts",
+                opts.target(),
+                opts.dns_retries + 1
+            );
+            return;
+        }
+        Err(AddressError::Unknown) => {
+            output!(out, "PING: {}: Name or service not known", opts.target());
+            return;
+        }
+        Err(AddressError::NoAddress) => {
+            output!(
+                out,
+                "PING: {}: No address associated with hostname",
+                opts.target()
+            );
             return;
         }
     };
+
     let wait_time = opts
         .send_interval
         .as_ref()
@@ -34,135 +160,1183 @@ fn main() {
     let read_timeout = opts
         .read_timeout
         .map_or(DEFAULT_READ_TIMEOUT, |s| Duration::from_secs(s as u64));
-    let ttl = opts.ttl;
-    let resource_name = opts.address;
-    let count_packets = opts.count_packets;
-    let p = ping::Settings {
-        addr: address.clone(),
-        ttl,
-        read_timeout,
+    let payload = match opts.payload_file.as_deref().map(read_payload).transpose() {
+        Ok(payload) => payload,
+        Err(err) => {
+            let path = opts.payload_file.as_ref().unwrap();
+            output!(out, "PING: {}: {}", path, io_error_to_string(err));
+            return;
+        }
+    };
+    // the compared and the failover hosts are pinged the same way so only the address differs
+    let settings = |addr| ping_settings(&opts, addr, read_timeout, payload.clone());
+    let address = match opts.failover {
+        Some(ms) => match failover(
+            &addresses,
+            Duration::from_millis(ms),
+            settings,
+            &opts,
+            &mut out,
+        ) {
+            Ok(Some(addr)) => {
+                output!(out, "PING: {} answered", addr);
+                addr
+            }
+            Ok(None) => {
+                output!(
+                    out,
+                    "PING: {}: No address answered in {}ms",
+                    opts.target(),
+                    ms
+                );
+                return;
+            }
+            // the error is reported once the socket is created again below
+            Err(..) => addresses[0],
+        },
+        None => addresses[0],
+    };
+    if opts.verbose {
+        match ping::source_address(address) {
+            Ok(source) => output!(out, "PING: source address {}", source),
+            Err(err) => output!(out, "PING: source address: {}", io_error_to_string(err)),
+        }
+    }
+
+    let pcap = match opts.pcap.as_ref().map(fs::File::create).transpose() {
+        Ok(file) => file,
+        Err(err) => {
+            output!(
+                out,
+                "PING: {}: {}",
+                opts.pcap.unwrap(),
+                io_error_to_string(err)
+            );
+            return;
+        }
+    };
+    let mut count_packets = opts.count_packets;
+    let target = ping::Settings {
+        pcap,
+        ..settings(address)
+    };
+    let mut p = match target.build() {
+        Ok(p) => p,
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            output!(
+                out,
+                "PING: operation not permitted: niping needs CAP_NET_RAW or root"
+            );
+            return;
+        }
+        Err(err) => {
+            output!(out, "PING: socket: {}", io_error_to_string(err));
+            return;
+        }
+    };
+
+    if opts.verbose {
+        // the kernel may double or clamp the sizes so we print the actual ones
+        let sock = p.socket().as_ref();
+        if let Ok(size) = sock.recv_buffer_size() {
+            output!(out, "PING: receive buffer size {} bytes", size);
+        }
+        if let Ok(size) = sock.send_buffer_size() {
+            output!(out, "PING: send buffer size {} bytes", size);
+        }
+        if let Ok(ttl) = sock.ttl() {
+            output!(out, "PING: outgoing TTL={}", ttl);
+        }
+    }
+
+    if let Some(path) = opts.replay.as_ref() {
+        let packets = match read_capture(path) {
+            Ok(packets) => packets,
+            Err(err) => {
+                output!(out, "PING: {}: {}", path, io_error_to_string(err));
+                return;
+            }
+        };
+
+        count_packets = Some(count_packets.map_or(packets.len(), |c| c.min(packets.len())));
+        p.replay(packets);
+    }
+
+    if opts.total_bytes.is_some() && p.payload_size() == 0 {
+        output!(out, "PING: --total-bytes requires requests with a payload");
+        return;
     }
-    .build();
 
     let stop = Arc::new(AtomicBool::default());
     let stop_copy = stop.clone();
     ctrlc::set_handler(move || stop_copy.as_ref().store(true, Ordering::Relaxed)).unwrap();
 
-    smol::run(run(
+    if let Some(sweep) = opts.size_sweep {
+        smol::run(size_sweep(
+            p, &opts, sweep, wait_time, stop, address, &mut out,
+        ));
+        return;
+    }
+
+    if let Some(other) = opts.compare.as_deref() {
+        let other_address = match parse_address(other) {
+            Ok(addresses) => addresses[0],
+            Err(..) => {
+                output!(out, "PING: {}: Name or service not known", other);
+                return;
+            }
+        };
+        // only the target's packets are written to the capture
+        let other_ping = match settings(other_address).build() {
+            Ok(p) => p,
+            Err(err) => {
+                output!(out, "PING: socket: {}", io_error_to_string(err));
+                return;
+            }
+        };
+
+        let pings = [(p, address), (other_ping, other_address)];
+        smol::run(compare(
+            pings,
+            &opts,
+            wait_time,
+            count_packets,
+            stop,
+            &mut out,
+        ));
+        return;
+    }
+
+    let (sent, received, rtt) = smol::run(run(
         p,
+        &opts,
         wait_time,
         count_packets,
         stop,
-        &address.to_string(),
-        &resource_name,
+        address,
+        &mut out,
     ));
+
+    // error messages like "administratively prohibited" don't prove the host is up
+    if opts.require_echo && received == 0 {
+        std::process::exit(1);
+    }
+
+    let violations = violated_criteria(opts.max_loss, opts.max_avg_rtt, sent, received, &rtt);
+    for violation in &violations {
+        output!(out, "PING: {}", violation);
+    }
+    if !violations.is_empty() {
+        std::process::exit(1);
+    }
 }
 
 async fn run(
     mut ping: ping::Ping<Socket2>,
+    opts: &args::Opts,
     wait_time: Duration,
     count_packets: Option<usize>,
     stop: Arc<AtomicBool>,
-    address: &str,
-    resource: &str,
-) {
+    address: IpAddr,
+    out: &mut Printer,
+) -> (usize, usize, RttStats) {
+    let resource = opts.target();
     let mut transmitted = 0usize;
     let mut received = 0usize;
-    let mut rtt: Vec<Duration> = Vec::new();
+    let mut rtt = match opts.trimmed_mean {
+        Some(..) => RttStats::with_reservoir(TRIMMED_MEAN_SAMPLES),
+        None => RttStats::default(),
+    };
+    let mut srtt = None;
+    // the last requests without a reply
+    let mut lost = VecDeque::with_capacity(LOST_WINDOW);
+    let mut sent = 0usize;
+    let mut responders = HashSet::new();
+    // the number of messages of each type and code by the host which sent them
+    let mut audit: BTreeMap<IpAddr, BTreeMap<(u8, u8), usize>> = BTreeMap::new();
+    let names = Names::default();
+    // the last error and the number of times it was repeated in a row
+    let mut repeated: Option<(String, usize)> = None;
+    // the number of send and receive errors in a row
+    let mut failures = 0;
+    let mut reconnections = 0;
+    // since when sends fail because the network is down
+    let mut network_down: Option<time::Instant> = None;
+    let mut downtime = Duration::default();
+    let total = count_packets;
+    let burst = opts.burst.unwrap_or(1).max(1);
     let mut count_packets = count_packets;
+    // the number of payload bytes sent, --total-bytes stops once it's reached
+    let mut sent_bytes = 0usize;
     let time = time::Instant::now();
+    let mut target = address;
+    let mut resolved = time::Instant::now();
+    // whether the host answers and since when
+    let mut state: Option<(bool, time::Instant)> = None;
+    // the address replies are expected to be sent to
+    let mut source = if opts.strict_destination {
+        ping::source_address(target).ok()
+    } else {
+        None
+    };
+    let dots = match opts.output {
+        Some(Output::Dots) => true,
+        Some(Output::Lines) => false,
+        None => wait_time < DOTS_INTERVAL,
+    };
+    let colored = match opts.color {
+        _ if opts.jsonl => false,
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+    };
 
-    println!(
-        "PING {} ({}) {} bytes of data",
-        address, resource, DATA_SIZE,
-    );
+    if !opts.jsonl && !opts.no_header {
+        output!(
+            out,
+            "PING {} ({}) {} bytes of data",
+            address,
+            resource,
+            ping.payload_size(),
+        );
+    }
 
     while !stop.as_ref().load(Ordering::Relaxed) {
-        match count_packets.as_mut() {
+        if let Some(secs) = opts.reresolve {
+            if resolved.elapsed() >= Duration::from_secs(secs) {
+                resolved = time::Instant::now();
+                // a round robin name may return the addresses in any order
+                // so we switch only if the current one is gone
+                match reresolve(resource).await {
+                    Ok(addresses) if !addresses.contains(&target) => {
+                        target = addresses[0];
+                        ping.set_destination(target);
+                        if opts.strict_destination {
+                            source = ping::source_address(target).ok();
+                        }
+                        output!(out, "PING: target changed to {}", target);
+                    }
+                    Ok(..) => (),
+                    Err(..) if opts.verbose => output!(out, "PING: {}: resolve failed", resource),
+                    Err(..) => (),
+                }
+            }
+        }
+
+        let probes = match count_packets.as_mut() {
             Some(0) => break,
-            Some(count) => *count -= 1,
-            None => (),
+            Some(count) => {
+                let probes = burst.min(*count);
+                *count -= probes;
+                probes
+            }
+            None => burst,
+        };
+
+        if opts.total_bytes.map_or(false, |total| sent_bytes >= total) {
+            break;
         }
 
-        let packet = ping.run().await;
-        match packet {
-            Ok(packet) => {
+        // the payload may grow after the requests
+        sent_bytes += probes * ping.payload_size();
+        let packets = match probes {
+            _ if opts.broadcast => ping.broadcast().await,
+            1 => vec![ping.run().await],
+            n => ping.burst(n).await,
+        };
+
+        // give the replies to the last requests a chance to arrive
+        let late = match (count_packets, opts.grace) {
+            (Some(0), Some(grace)) => ping.wait_late(Duration::from_millis(grace)).await,
+            _ => Vec::new(),
+        };
+
+        // late replies answer requests which are already accounted as timed out
+        let packets = packets.into_iter().map(|packet| (packet, false));
+        let late = late.into_iter().map(|packet| (packet, true));
+        let mut answered = false;
+        for (i, (packet, late)) in packets.chain(late).enumerate() {
+            // every host answers a broadcast request
+            // so the replies after the first one don't count as requests
+            let extra = opts.broadcast && i > 0;
+            if !late && !extra {
+                sent += 1;
+            }
+            let event = PingEvent::from(packet);
+            let reply = matches!(event, PingEvent::Reply(..));
+            let timed_out = matches!(event, PingEvent::Timeout { .. });
+            let color = match &event {
+                PingEvent::Reply(..) => Some(GREEN),
+                PingEvent::NonEcho(info) => PacketType::new(info.icmp_type)
+                    .filter(PacketType::is_error)
+                    .map(|_| YELLOW),
+                _ => Some(RED),
+            };
+            let failed = !matches!(event, PingEvent::Reply(..) | PingEvent::NonEcho(..));
+            // a request is transmitted whether it's answered or not
+            if !late && !extra && !matches!(event, PingEvent::SendError(..)) {
                 transmitted += 1;
-                rtt.push(packet.time);
-                if let Some(PacketType::EchoReply) = PacketType::new(packet.icmp_type) {
-                    received += 1;
+            }
+            let down = match &event {
+                PingEvent::SendError(err) => ping::is_network_down(err),
+                _ => false,
+            };
+            match network_down {
+                None if down => {
+                    network_down = Some(time::Instant::now());
+                    output!(out, "PING: network down, waiting...");
+                }
+                // any other outcome means the request was sent
+                Some(since) if !matches!(event, PingEvent::SendError(..)) => {
+                    let elapsed = since.elapsed();
+                    downtime += elapsed;
+                    network_down = None;
+                    output!(out, "PING: network up after {}", display_duration(elapsed));
+                }
+                _ => (),
+            }
+            let line = match event {
+                PingEvent::Reply(packet) | PingEvent::NonEcho(packet) => {
+                    let spike = opts
+                        .spike_threshold
+                        .and_then(|factor| rtt.min().map(|min| min.mul_f32(factor)))
+                        .map_or(false, |threshold| packet.time > threshold);
+
+                    failures = 0;
+                    lost.retain(|&seq| seq != packet.icmp_seq);
+                    responders.insert(packet.ip_source_ip);
+                    *audit
+                        .entry(packet.ip_source_ip)
+                        .or_default()
+                        .entry((packet.icmp_type, packet.icmp_code))
+                        .or_default() += 1;
+                    rtt.push(packet.time);
+                    srtt = Some(smooth_rtt(srtt, packet.time));
+                    let too_slow = opts
+                        .max_rtt
+                        .map_or(false, |ms| packet.time > Duration::from_millis(ms));
+                    if reply
+                        && (!too_slow || opts.slow_as_received)
+                        && !(opts.broadcast && answered)
+                    {
+                        received += 1;
+                        answered = true;
+                    }
+
+                    if opts.jsonl {
+                        let name = names.get(packet.ip_source_ip).flatten();
+                        packet_json(&packet, name.as_deref())
+                    } else {
+                        let destination = packet.ip_destination_ip;
+                        let mut line = display_packet(packet, &names, opts);
+                        if spike {
+                            line += " (spike)";
+                        }
+                        if too_slow {
+                            line += " (too slow)";
+                        }
+                        match source {
+                            Some(source) if source != destination => {
+                                line += &format!(" (sent to {}, not {})", destination, source)
+                            }
+                            _ => (),
+                        }
+                        if let (true, Some(total)) = (opts.show_remaining, total) {
+                            line += &format!(" ({} left)", total.saturating_sub(sent));
+                        }
+
+                        line
+                    }
                 }
+                event => {
+                    match event {
+                        PingEvent::Timeout { seq } => {
+                            if lost.len() == LOST_WINDOW {
+                                lost.pop_front();
+                            }
+                            lost.push_back(seq);
+                        }
+                        PingEvent::SendError(..) | PingEvent::RecvError(..) => failures += 1,
+                        _ => (),
+                    }
 
-                println!("{}", display_packet(packet));
+                    if opts.jsonl {
+                        error_json(&event)
+                    } else {
+                        display_error(event)
+                    }
+                }
+            };
+
+            if down {
+                // the errors are reported once by the network down message
+            } else if opts.jsonl {
+                output!(out, "{}", line);
+            } else if opts.changes_only {
+                let up = match (reply, timed_out) {
+                    (true, _) => Some(true),
+                    (_, true) => Some(false),
+                    _ => None,
+                };
+
+                match (up, state) {
+                    (Some(up), Some((was_up, _))) if up == was_up => (),
+                    (Some(up), _) => {
+                        let now = display_timestamp(SystemTime::now(), opts.timestamp_format);
+                        match state {
+                            _ if !up => output!(out, "{} DOWN at {}", resource, now),
+                            Some((_, since)) => output!(
+                                out,
+                                "{} UP at {} after {}",
+                                resource,
+                                now,
+                                display_duration(since.elapsed())
+                            ),
+                            None => output!(out, "{} UP at {}", resource, now),
+                        }
+                        state = Some((up, time::Instant::now()));
+                    }
+                    (None, _) => (),
+                }
+            } else if opts.progress {
+                print_progress(out, sent, total, received, time.elapsed());
+            } else if dots {
+                if failed {
+                    print!(".");
+                    let _ = io::stdout().flush();
+                }
+            } else {
+                let text = if opts.timestamp {
+                    let now = display_timestamp(SystemTime::now(), opts.timestamp_format);
+                    format!("[{}] {}", now, line)
+                } else {
+                    line.clone()
+                };
+                let text = out.labeled(&text);
+                out.log(&text);
+                let text = match color {
+                    Some(color) if colored => format!("\x1b[{}m{}\x1b[0m", color, text),
+                    _ => text,
+                };
+
+                match repeated.as_mut() {
+                    Some((last, count)) if failed && *last == line => {
+                        *count += 1;
+                        print!("\r{} (x{})", text, count);
+                        let _ = io::stdout().flush();
+                    }
+                    _ if failed && opts.quiet_errors => {
+                        if repeated.is_some() {
+                            println!();
+                        }
+                        print!("{}", text);
+                        let _ = io::stdout().flush();
+                        repeated = Some((line, 1));
+                    }
+                    _ => {
+                        if repeated.take().is_some() {
+                            println!();
+                        }
+                        println!("{}", text);
+                        let _ = io::stdout().flush();
+                    }
+                }
+            }
+
+            // the socket may be broken after a network failure so we recreate it
+            if failures >= REOPEN_AFTER {
+                failures = 0;
+                match ping.reopen() {
+                    Ok(()) => reconnections += 1,
+                    Err(err) => output!(out, "PING: reopen: {}", io_error_to_string(err)),
+                }
             }
-            Err(PingError::Send(err)) => println!("send: {}", io_error_to_string(err)),
-            Err(PingError::Recv(err)) => println!("recv: {}", io_error_to_string(err)),
-            Err(PingError::PacketError(..)) => println!("internal error"),
         }
 
+        let wait_time = match opts.interval_jitter {
+            Some(fraction) => jitter(wait_time, fraction),
+            None => wait_time,
+        };
+        let wait_time = match network_down {
+            Some(..) => wait_time.max(NETWORK_DOWN_INTERVAL),
+            None => wait_time,
+        };
         smol::Timer::after(wait_time).await;
     }
 
-    let time = time.elapsed();
+    // finish the line of a collapsed error
+    if repeated.is_some() {
+        println!();
+    }
 
-    let rtt_min = rtt.iter().min().unwrap();
-    let rtt_max = rtt.iter().max().unwrap();
-    let rtt_len = rtt.len();
-    let rtt_avg = rtt.iter().sum::<Duration>() / rtt_len as u32;
+    if let Some(path) = opts.prometheus.as_deref() {
+        if let Err(err) = write_prometheus(path, opts.target(), sent, received, &rtt) {
+            output!(out, "PING: {}: {}", path, io_error_to_string(err));
+        }
+    }
 
-    println!();
-    println!("------- {} statistics -------", resource);
-    println!(
-        "{} packets transmitted, received {}, time {}",
+    if let Some(since) = network_down {
+        downtime += since.elapsed();
+    }
+
+    let footer = Footer {
         transmitted,
         received,
-        display_duration(time)
+        time: time.elapsed(),
+        rtt: &rtt,
+        rate_limited: is_rate_limited(lost.make_contiguous()),
+        trimmed_mean: opts
+            .trimmed_mean
+            .and_then(|pct| rtt.trimmed_mean(pct).map(|avg| (pct, avg))),
+        srtt: if opts.srtt { srtt } else { None },
+        responders: if opts.broadcast {
+            Some(responders.len())
+        } else {
+            None
+        },
+        oversized: ping.oversized(),
+        reconnections,
+        sent_bytes: opts.total_bytes.map(|_| sent_bytes),
+        downtime,
+        audit: if opts.audit { Some(&audit) } else { None },
+    };
+    if opts.jsonl {
+        output!(out, "{}", summary_json(&footer));
+    } else {
+        print_summary(out, opts, &footer);
+    }
+
+    (sent, received, rtt)
+}
+
+/// The statistics printed at the end of a run.
+///
+/// Both the text and the JSON summary are rendered from it
+/// so they report the same fields.
+struct Footer<'a> {
+    transmitted: usize,
+    received: usize,
+    time: Duration,
+    rtt: &'a RttStats,
+    /// Whether the requests were lost with a regular cadence.
+    rate_limited: bool,
+    /// The percent trimmed by --trimmed-mean and the average of the rest.
+    trimmed_mean: Option<(f64, Duration)>,
+    /// The smoothed rtt and its variation if --srtt is set.
+    srtt: Option<(Duration, Duration)>,
+    /// The number of unique hosts which answered a broadcast.
+    responders: Option<usize>,
+    oversized: usize,
+    reconnections: usize,
+    /// The number of payload bytes sent if --total-bytes is set.
+    sent_bytes: Option<usize>,
+    downtime: Duration,
+    /// The messages of each responder if --audit is set.
+    audit: Option<&'a BTreeMap<IpAddr, BTreeMap<(u8, u8), usize>>>,
+}
+
+/// Prints every host which answered with the messages it sent.
+fn print_audit(out: &mut Printer, audit: &BTreeMap<IpAddr, BTreeMap<(u8, u8), usize>>) {
+    output!(out, "");
+    output!(out, "------- responders -------");
+    for (host, messages) in audit {
+        let messages = messages
+            .iter()
+            .map(|(&(tp, code), count)| {
+                let name = icmp::describe(tp, code);
+                format!("{} ({}/{}) x{}", name, tp, code, count)
+            })
+            .collect::<Vec<_>>();
+        output!(out, "{}: {}", host, messages.join(", "));
+    }
+}
+
+/// Checks the final statistics against --max-loss and --max-avg-rtt
+/// and returns a message per violated one.
+fn violated_criteria(
+    max_loss: Option<f64>,
+    max_avg_rtt: Option<u64>,
+    sent: usize,
+    received: usize,
+    rtt: &RttStats,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+
+    if let Some(max_loss) = max_loss {
+        let loss = if sent == 0 {
+            100.0
+        } else {
+            sent.saturating_sub(received) as f64 / sent as f64 * 100.0
+        };
+        if loss >= max_loss {
+            violations.push(format!(
+                "loss {:.1}% isn't below --max-loss {}%",
+                loss, max_loss
+            ));
+        }
+    }
+
+    if let Some(ms) = max_avg_rtt {
+        let limit = Duration::from_millis(ms);
+        match rtt.avg() {
+            Some(avg) if avg >= limit => violations.push(format!(
+                "avg rtt {} isn't below --max-avg-rtt {}ms",
+                display_duration(avg),
+                ms
+            )),
+            Some(..) => (),
+            None => violations.push(String::from("no rtt to check --max-avg-rtt against")),
+        }
+    }
+
+    violations
+}
+
+/// Sends a few requests of each payload size and prints the loss per size.
+///
+/// Size dependent loss points to a broken PMTU discovery or a middlebox.
+async fn size_sweep(
+    mut ping: ping::Ping<Socket2>,
+    opts: &args::Opts,
+    sweep: SizeSweep,
+    wait_time: Duration,
+    stop: Arc<AtomicBool>,
+    address: IpAddr,
+    out: &mut Printer,
+) {
+    let probes = opts.count_packets.unwrap_or(SWEEP_PROBES);
+    let mut largest = None;
+
+    output!(
+        out,
+        "PING {} ({}) sizes from {} to {} by {} bytes",
+        address,
+        opts.target(),
+        sweep.start,
+        sweep.end,
+        sweep.step
+    );
+    output!(out, "{:>8} {:>10} {:>7}", "size", "received", "loss");
+
+    for size in (sweep.start..=sweep.end).step_by(sweep.step) {
+        ping.set_payload_size(size);
+
+        let mut sent = 0;
+        let mut received = 0;
+        while sent < probes && !stop.as_ref().load(Ordering::Relaxed) {
+            sent += 1;
+            if let PingEvent::Reply(..) = PingEvent::from(ping.run().await) {
+                received += 1;
+            }
+
+            smol::Timer::after(wait_time).await;
+        }
+
+        if sent == 0 {
+            break;
+        }
+
+        let loss = (sent - received) as f64 / sent as f64 * 100.0;
+        let replies = format!("{}/{}", received, sent);
+        output!(out, "{:>8} {:>10} {:>6.1}%", size, replies, loss);
+
+        if received > 0 {
+            largest = Some(size);
+        }
+    }
+
+    match largest {
+        Some(size) => output!(out, "largest size with replies {} bytes", size),
+        None => output!(out, "no replies at any size"),
+    }
+}
+
+/// Pings two hosts at once and prints the difference of their rtt.
+///
+/// The summary tells which one was faster more often.
+async fn compare(
+    pings: [(ping::Ping<Socket2>, IpAddr); 2],
+    opts: &args::Opts,
+    wait_time: Duration,
+    count_packets: Option<usize>,
+    stop: Arc<AtomicBool>,
+    out: &mut Printer,
+) {
+    let [(mut a, a_address), (mut b, b_address)] = pings;
+    // the number of times each host was faster
+    let mut faster = (0, 0);
+    let mut sent = 0;
+
+    output!(out, "PING {} (A) and {} (B)", a_address, b_address);
+
+    while !stop.as_ref().load(Ordering::Relaxed) && count_packets.map_or(true, |c| sent < c) {
+        sent += 1;
+        // the requests are sent at once so both hosts are measured at the same moment
+        let (a_packet, b_packet) = join(a.run(), b.run()).await;
+        let rtt_a = reply_time(a_packet);
+        let rtt_b = reply_time(b_packet);
+
+        let display = |rtt: Option<Duration>| {
+            rtt.map_or(String::from("timeout"), |rtt| display_rtt(rtt, opts.unit))
+        };
+        let delta = match (rtt_a, rtt_b) {
+            (Some(rtt_a), Some(rtt_b)) if rtt_a < rtt_b => {
+                faster.0 += 1;
+                format!(" Δ=-{}", display_rtt(rtt_b - rtt_a, opts.unit))
+            }
+            (Some(rtt_a), Some(rtt_b)) => {
+                if rtt_a > rtt_b {
+                    faster.1 += 1;
+                }
+                format!(" Δ=+{}", display_rtt(rtt_a - rtt_b, opts.unit))
+            }
+            _ => String::new(),
+        };
+        output!(
+            out,
+            "seq={} A={} B={}{}",
+            sent,
+            display(rtt_a),
+            display(rtt_b),
+            delta
+        );
+
+        smol::Timer::after(wait_time).await;
+    }
+
+    output!(out, "");
+    output!(
+        out,
+        "------- {} vs {} statistics -------",
+        a_address,
+        b_address
     );
-    println!(
-        "rtt min/max/avg = {}/{}/{}",
-        display_duration(*rtt_min),
-        display_duration(*rtt_max),
-        display_duration(rtt_avg),
+    output!(
+        out,
+        "A was faster {} times, B was faster {} times",
+        faster.0,
+        faster.1
     );
 }
 
-fn display_packet(info: PacketInfo) -> String {
-    let specific_info = packet_info(&info);
-    let dns_name =
-        reverse_address(IpAddr::from(info.ip_source_ip)).map_or(String::from("gateway"), |n| n);
+/// Polls both futures in turns until each of them completes.
+async fn join<A, B>(a: A, b: B) -> (A::Output, B::Output)
+where
+    A: Future,
+    B: Future,
+    A::Output: Unpin,
+    B::Output: Unpin,
+{
+    Join {
+        a: Box::pin(a),
+        b: Box::pin(b),
+        outputs: (None, None),
+    }
+    .await
+}
 
-    format!(
-        "{} bytes from {} ({}): {}",
-        info.received_bytes, dns_name, info.ip_source_ip, specific_info
-    )
+struct Join<A: Future, B: Future> {
+    a: Pin<Box<A>>,
+    b: Pin<Box<B>>,
+    outputs: (Option<A::Output>, Option<B::Output>),
 }
 
-fn packet_info(info: &PacketInfo) -> String {
-    use PacketType::*;
+impl<A, B> Future for Join<A, B>
+where
+    A: Future,
+    B: Future,
+    A::Output: Unpin,
+    B::Output: Unpin,
+{
+    type Output = (A::Output, B::Output);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let join = &mut *self;
+        if join.outputs.0.is_none() {
+            if let Poll::Ready(output) = join.a.as_mut().poll(cx) {
+                join.outputs.0 = Some(output);
+            }
+        }
+        if join.outputs.1.is_none() {
+            if let Poll::Ready(output) = join.b.as_mut().poll(cx) {
+                join.outputs.1 = Some(output);
+            }
+        }
+
+        match &mut join.outputs {
+            (a @ Some(..), b @ Some(..)) => Poll::Ready((a.take().unwrap(), b.take().unwrap())),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Returns the rtt of an echo reply, other outcomes have no comparable one.
+fn reply_time(packet: ping::Result<PacketInfo>) -> Option<Duration> {
+    match PingEvent::from(packet) {
+        PingEvent::Reply(info) => Some(info.time),
+        _ => None,
+    }
+}
+
+/// Builds an echo request in an ip datagram and parses it back
+/// to check the packet code works on the platform without privileges.
+fn selftest() -> Result<(), String> {
+    fn ensure(ok: bool, what: &str) -> Result<(), String> {
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("{} doesn't survive a round trip", what))
+        }
+    }
+
+    let payload = (0..32).collect::<Vec<u8>>();
+    let request = icmp::EchoRequest::new(0x1234, 7).with_payload(&payload);
+    let mut buf = [0; 1500];
+    let icmp_size = request
+        .build(&mut buf)
+        .map_err(|err| format!("build echo request: {:?}", err))?;
+    let icmp = &buf[..icmp_size];
+
+    let packet = IcmpPacket::parse(icmp).map_err(|err| format!("parse echo request: {:?}", err))?;
+    ensure(packet.tp() == PacketType::EchoRequest as u8, "type")?;
+    ensure(packet.ident() == 0x1234, "identifier")?;
+    ensure(packet.seq() == 7, "sequence number")?;
+    ensure(packet.payload() == &payload[..], "payload")?;
+    ensure(packet.is_checksum_correct(), "checksum")?;
+
+    let source = Ipv4Addr::new(192, 0, 2, 1);
+    let destination = Ipv4Addr::new(192, 0, 2, 2);
+    let mut datagram = [0; 1500];
+    let size = IPV4Builder::new(64, ip::Protocol::ICMP, source, destination, icmp)
+        .build(&mut datagram)
+        .map_err(|err| format!("build ip datagram: {:?}", err))?;
+
+    let ip = IPV4Packet::parse(&datagram[..size])
+        .map_err(|err| format!("parse ip datagram: {:?}", err))?;
+    ensure(ip.ttl() == 64, "ttl")?;
+    ensure(ip.protocol() == ip::Protocol::ICMP as u8, "protocol")?;
+    ensure(ip.source_ip() == source, "source address")?;
+    ensure(ip.destination_ip() == destination, "destination address")?;
+    ensure(ip.payload() == Some(icmp), "ip payload")?;
+
+    Ok(())
+}
+
+/// Redraws the progress line in place.
+fn print_progress(
+    out: &Printer,
+    sent: usize,
+    total: Option<usize>,
+    received: usize,
+    elapsed: Duration,
+) {
+    let total = total.map_or(String::new(), |total| format!("/{}", total));
+    let loss = (sent - received) as f64 / sent as f64 * 100.0;
+    let line = format!(
+        "sent {}{}, received {}, loss {:.1}%, elapsed {}",
+        sent,
+        total,
+        received,
+        loss,
+        display_duration(elapsed)
+    );
+    print!("\r{}", out.labeled(&line));
+    let _ = io::stdout().flush();
+}
+
+/// Randomizes the interval by up to the fraction of it in both directions
+/// so probes of many instances don't get synchronized.
+fn jitter(interval: Duration, fraction: f32) -> Duration {
+    let offset = rand::random::<f32>() * 2.0 - 1.0;
+    interval.mul_f32((1.0 + fraction * offset).max(0.0))
+}
+
+/// Computes the smoothed rtt and the rtt variation by Jacobson's algorithm
+/// the same way TCP does for its retransmission timer.
+///
+/// rfc6298 section 2
+fn smooth_rtt(state: Option<(Duration, Duration)>, r: Duration) -> (Duration, Duration) {
+    match state {
+        None => (r, r / 2),
+        Some((srtt, rttvar)) => {
+            let diff = if srtt > r { srtt - r } else { r - srtt };
+            let rttvar = rttvar * 3 / 4 + diff / 4;
+            let srtt = srtt * 7 / 8 + r / 8;
+            (srtt, rttvar)
+        }
+    }
+}
+
+/// Prints the statistics footer.
+///
+/// It's the only place the footer is produced so it looks the same
+/// whether the loop was stopped by a count or by an interruption.
+fn print_summary(out: &mut Printer, opts: &args::Opts, footer: &Footer) {
+    output!(out, "");
+    output!(out, "------- {} statistics -------", opts.target());
+    output!(
+        out,
+        "{} packets transmitted, received {}, time {}",
+        footer.transmitted,
+        footer.received,
+        display_duration(footer.time)
+    );
+
+    if footer.rate_limited {
+        output!(out, "(possible ICMP rate limiting)");
+    }
+
+    // there's no rtt to report if we haven't got any answer
+    let rtt = footer.rtt;
+    if let (Some(rtt_min), Some(rtt_max), Some(rtt_avg)) = (rtt.min(), rtt.max(), rtt.avg()) {
+        output!(
+            out,
+            "rtt min/max/avg = {}/{}/{}",
+            display_rtt(rtt_min, opts.unit),
+            display_rtt(rtt_max, opts.unit),
+            display_rtt(rtt_avg, opts.unit),
+        );
+    }
+
+    if let Some((pct, trimmed)) = footer.trimmed_mean {
+        let trimmed = display_rtt(trimmed, opts.unit);
+        output!(out, "rtt trimmed avg ({}%) = {}", pct, trimmed);
+    }
+
+    if let Some((srtt, rttvar)) = footer.srtt {
+        output!(
+            out,
+            "srtt/rttvar = {}/{}",
+            display_rtt(srtt, opts.unit),
+            display_rtt(rttvar, opts.unit),
+        );
+    }
+
+    if let Some(responders) = footer.responders {
+        output!(out, "{} unique responders", responders);
+    }
+
+    if footer.oversized > 0 {
+        output!(out, "{} oversized replies discarded", footer.oversized);
+    }
+
+    if footer.reconnections > 0 {
+        output!(out, "socket reopened {} times", footer.reconnections);
+    }
+
+    if let Some(bytes) = footer.sent_bytes {
+        output!(out, "{} bytes of payload sent", bytes);
+    }
+
+    if footer.downtime > Duration::default() {
+        output!(
+            out,
+            "network down for {}",
+            display_duration(footer.downtime)
+        );
+    }
+
+    if let Some(audit) = footer.audit {
+        print_audit(out, audit);
+    }
+}
+
+/// Writes the statistics in the Prometheus text format
+/// for the textfile collector of node_exporter.
+///
+/// The file is replaced atomically so the collector never reads a partial one.
+fn write_prometheus(
+    path: &str,
+    target: &str,
+    sent: usize,
+    received: usize,
+    rtt: &RttStats,
+) -> io::Result<()> {
+    let target = target
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    let labels = format!("target=\"{}\"", target);
+    let loss = if sent == 0 {
+        0.0
+    } else {
+        sent.saturating_sub(received) as f64 / sent as f64
+    };
+
+    let mut metrics = String::new();
+    if !rtt.is_empty() {
+        let rtt_min = rtt.min().unwrap();
+        let rtt_max = rtt.max().unwrap();
+        let rtt_avg = rtt.avg().unwrap();
+
+        metrics += "# HELP niping_rtt_seconds Round trip time of echo requests.\n";
+        metrics += "# TYPE niping_rtt_seconds gauge\n";
+        for (stat, rtt) in &[("min", rtt_min), ("max", rtt_max), ("avg", rtt_avg)] {
+            metrics += &format!(
+                "niping_rtt_seconds{{{},stat=\"{}\"}} {}\n",
+                labels,
+                stat,
+                rtt.as_secs_f64()
+            );
+        }
+    }
+
+    metrics += "# HELP niping_packets_sent_total Number of sent requests.\n";
+    metrics += "# TYPE niping_packets_sent_total counter\n";
+    metrics += &format!("niping_packets_sent_total{{{}}} {}\n", labels, sent);
+    metrics += "# HELP niping_packets_received_total Number of received echo replies.\n";
+    metrics += "# TYPE niping_packets_received_total counter\n";
+    metrics += &format!("niping_packets_received_total{{{}}} {}\n", labels, received);
+    metrics += "# HELP niping_packet_loss_ratio Ratio of requests without an echo reply.\n";
+    metrics += "# TYPE niping_packet_loss_ratio gauge\n";
+    metrics += &format!("niping_packet_loss_ratio{{{}}} {}\n", labels, loss);
+
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, metrics)?;
+    fs::rename(&tmp, path)
+}
+
+/// Checks whether requests are lost with a regular cadence
+/// e.g. every 5th one, which is how routers limiting ICMP rate usually drop them.
+///
+/// The lost sequence numbers are expected to be in the order they were sent.
+fn is_rate_limited(lost: &[u16]) -> bool {
+    if lost.len() < 3 {
+        return false;
+    }
+
+    let gap = lost[1].wrapping_sub(lost[0]);
+    gap > 1 && lost.windows(2).all(|w| w[1].wrapping_sub(w[0]) == gap)
+}
+
+fn read_payload(path: &str) -> io::Result<Vec<u8>> {
+    let payload = fs::read(path)?;
+    if payload.len() > MAXIMUM_PAYLOAD_SIZE {
+        let msg = format!("payload exceeds {} bytes", MAXIMUM_PAYLOAD_SIZE);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+
+    Ok(payload)
+}
+
+/// Reads ICMP requests from a pcap file.
+///
+/// Replies and errors are skipped since there's no point to send them.
+fn read_capture(path: &str) -> io::Result<Vec<IcmpBuilder>> {
+    capture_requests(pcap::Reader::new(fs::File::open(path)?)?)
+}
+
+/// Collects the requests of the capture which can be replayed.
+///
+/// The replies, errors and the rest of messages are skipped.
+fn capture_requests<R: io::Read>(mut capture: pcap::Reader<R>) -> io::Result<Vec<IcmpBuilder>> {
+    let mut packets = Vec::new();
+    while let Some(packet) = capture.next_packet()? {
+        let ip = match IPV4Packet::parse(&packet) {
+            Ok(ip) => ip,
+            Err(..) => continue,
+        };
+        let icmp = match ip.payload().map(IcmpPacket::parse) {
+            Some(Ok(icmp)) => icmp,
+            _ => continue,
+        };
+
+        use PacketType::*;
+        if let Some(EchoRequest) | Some(Timestamp) | Some(ExtendedEchoRequest) =
+            PacketType::new(icmp.tp())
+        {
+            packets.push(IcmpBuilder::from(&icmp));
+        }
+    }
+
+    Ok(packets)
+}
+
+fn display_packet(info: PacketInfo, names: &Names, opts: &args::Opts) -> String {
+    let specific_info = packet_info(&info, opts);
+    let dns_name = match names.get(info.ip_source_ip) {
+        Some(Some(name)) => name,
+        Some(None) => String::from("gateway"),
+        // the address stands in for the name until the lookup completes
+        None => info.ip_source_ip.to_string(),
+    };
+
+    let mut line = if opts.align {
+        let source = format!("{} ({}):", dns_name, info.ip_source_ip);
+        format!(
+            "{:>5} bytes from {:<w$} {}",
+            info.received_bytes,
+            source,
+            specific_info,
+            w = ALIGNED_SOURCE_WIDTH
+        )
+    } else {
+        format!(
+            "{} bytes from {} ({}): {}",
+            info.received_bytes, dns_name, info.ip_source_ip, specific_info
+        )
+    };
+
+    if info.retries > 0 {
+        line += &format!(" retries={}", info.retries);
+    }
+
+    if let Some(time) = info.payload_time {
+        line += &format!(" payload_time={}", display_rtt(time, opts.unit));
+    }
+
+    if opts.show_payload_len {
+        line += &format!(" (payload {} bytes)", info.icmp_payload_len);
+    }
+
+    if opts.show_ident {
+        line += &format!(" ident={}", info.icmp_ident);
+    }
+
+    if opts.hops {
+        line += &format!(" hops≈{}", estimate_hops(info.ip_ttl));
+    }
+
+    line
+}
+
+/// Estimates the number of hops on the return path
+/// supposing the reply was sent with one of the common initial TTL values.
+fn estimate_hops(ttl: u8) -> u8 {
+    let initial = [64, 128, 255]
+        .iter()
+        .copied()
+        .find(|&initial| initial >= ttl)
+        .unwrap_or(255);
+    initial - ttl
+}
+
+fn packet_info(info: &PacketInfo, opts: &args::Opts) -> String {
     match PacketType::new(info.icmp_type) {
-        Some(EchoReply) => format!(
+        Some(PacketType::EchoReply) | Some(PacketType::InformationReply) if opts.align => format!(
+            "icmp_seq={:<5} ttl={:<3} time={:>9}",
+            info.icmp_seq,
+            info.ip_ttl,
+            display_rtt(info.time, opts.unit)
+        ),
+        Some(PacketType::EchoReply) | Some(PacketType::InformationReply) => format!(
             "icmp_seq={} ttl={} time={}",
             info.icmp_seq,
             info.ip_ttl,
-            display_duration(info.time)
+            display_rtt(info.time, opts.unit)
         ),
-        Some(ref tp) => {
-            let message = match tp {
-                TimeExceeded => "time to live exceeded",
-                DestinationUnreachable => "destination unreachable",
-                ParameterProblem => "parameter problem",
-                RedirectMessage => "redirect message",
-                RouterAdvertisement => "router advertisement",
-                RouterSolicitation => "router solicitation",
-                Timestamp => "timestamp",
-                TimestampReply => "timestamp reply",
-                ExtendedEchoReply => "extended echo reply",
-                EchoRequest => "echo request",
-                ExtendedEchoRequest => "extended echo request",
-                EchoReply => "echo reply",
-            };
-
-            format!("icmp_seq={} {}", info.icmp_seq, message)
+        Some(..) => {
+            let mut description = icmp::describe(info.icmp_type, info.icmp_code).to_string();
+            if opts.show_type_code {
+                description += &format!(" (type {}, code {})", info.icmp_type, info.icmp_code);
+            }
+            match info.quoted.as_ref() {
+                Some(quoted) => format!(
+                    "icmp_seq={} {} (dst={} proto={})",
+                    info.icmp_seq, description, quoted.destination, quoted.protocol
+                ),
+                None => format!("icmp_seq={} {}", info.icmp_seq, description),
+            }
         }
         None => format!(
             "icmp_seq={}, nonstandard packet {}",
@@ -171,14 +1345,236 @@ fn packet_info(info: &PacketInfo) -> String {
     }
 }
 
+fn display_error(event: PingEvent) -> String {
+    match event {
+        PingEvent::SendError(err) => format!("send: {}", io_error_to_string(err)),
+        PingEvent::RecvError(err) => format!("recv: {}", io_error_to_string(err)),
+        PingEvent::Error(PingError::Record(err)) => format!("pcap: {}", io_error_to_string(err)),
+        PingEvent::Timeout { seq } => format!("request timeout for icmp_seq {}", seq),
+        _ => String::from("internal error"),
+    }
+}
+
+fn packet_json(info: &PacketInfo, name: Option<&str>) -> String {
+    format!(
+        r#"{{"seq":{},"source":"{}","name":{},"ttl":{},"type":{},"code":{},"bytes":{},"time_ms":{:.3}}}"#,
+        info.icmp_seq,
+        info.ip_source_ip,
+        name.map_or(String::from("null"), json_string),
+        info.ip_ttl,
+        info.icmp_type,
+        info.icmp_code,
+        info.received_bytes,
+        info.time.as_secs_f64() * 1e3,
+    )
+}
+
+fn error_json(event: &PingEvent) -> String {
+    let msg = match event {
+        PingEvent::Timeout { seq } => return format!(r#"{{"seq":{},"timeout":true}}"#, seq),
+        PingEvent::SendError(err) => format!("send: {}", err),
+        PingEvent::RecvError(err) => format!("recv: {}", err),
+        PingEvent::Error(PingError::Record(err)) => format!("pcap: {}", err),
+        _ => String::from("internal error"),
+    };
+
+    format!(r#"{{"error":{}}}"#, json_string(&msg))
+}
+
+fn summary_json(footer: &Footer) -> String {
+    let mut json = format!(
+        r#"{{"transmitted":{},"received":{},"time_ms":{:.3}"#,
+        footer.transmitted,
+        footer.received,
+        footer.time.as_secs_f64() * 1e3
+    );
+
+    let rtt = footer.rtt;
+    if let (Some(rtt_min), Some(rtt_max), Some(rtt_avg)) = (rtt.min(), rtt.max(), rtt.avg()) {
+        json += &format!(
+            r#","rtt_min_ms":{:.3},"rtt_max_ms":{:.3},"rtt_avg_ms":{:.3}"#,
+            rtt_min.as_secs_f64() * 1e3,
+            rtt_max.as_secs_f64() * 1e3,
+            rtt_avg.as_secs_f64() * 1e3
+        );
+    }
+
+    if footer.rate_limited {
+        json += r#","rate_limited":true"#;
+    }
+
+    if let Some((pct, trimmed)) = footer.trimmed_mean {
+        json += &format!(
+            r#","rtt_trimmed_pct":{},"rtt_trimmed_avg_ms":{:.3}"#,
+            pct,
+            trimmed.as_secs_f64() * 1e3
+        );
+    }
+
+    if let Some((srtt, rttvar)) = footer.srtt {
+        json += &format!(
+            r#","srtt_ms":{:.3},"rttvar_ms":{:.3}"#,
+            srtt.as_secs_f64() * 1e3,
+            rttvar.as_secs_f64() * 1e3
+        );
+    }
+
+    if let Some(responders) = footer.responders {
+        json += &format!(r#","responders":{}"#, responders);
+    }
+
+    if footer.oversized > 0 {
+        json += &format!(r#","oversized":{}"#, footer.oversized);
+    }
+
+    if footer.reconnections > 0 {
+        json += &format!(r#","reconnections":{}"#, footer.reconnections);
+    }
+
+    if let Some(bytes) = footer.sent_bytes {
+        json += &format!(r#","sent_bytes":{}"#, bytes);
+    }
+
+    if footer.downtime > Duration::default() {
+        json += &format!(
+            r#","downtime_ms":{:.3}"#,
+            footer.downtime.as_secs_f64() * 1e3
+        );
+    }
+
+    if let Some(audit) = footer.audit {
+        let hosts = audit
+            .iter()
+            .map(|(host, messages)| {
+                let messages = messages
+                    .iter()
+                    .map(|((tp, code), count)| {
+                        format!(r#"{{"type":{},"code":{},"count":{}}}"#, tp, code, count)
+                    })
+                    .collect::<Vec<_>>();
+                format!(r#""{}":[{}]"#, host, messages.join(","))
+            })
+            .collect::<Vec<_>>();
+        json += &format!(r#","audit":{{{}}}"#, hosts.join(","));
+    }
+
+    json + "}"
+}
+
+/// Quotes the string escaping the characters JSON doesn't allow in strings.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 fn display_duration(d: Duration) -> String {
     format!("{:.2?}", d)
 }
 
+/// Displays rtt in the fixed unit if it's set so the values are easy to compare.
+fn display_rtt(d: Duration, unit: Option<Unit>) -> String {
+    match unit {
+        Some(Unit::Milliseconds) => format!("{:.2} ms", d.as_secs_f64() * 1e3),
+        Some(Unit::Microseconds) => format!("{:.2} us", d.as_secs_f64() * 1e6),
+        None => display_duration(d),
+    }
+}
+
+fn display_timestamp(time: SystemTime, format: TimestampFormat) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match format {
+        TimestampFormat::Unix => format!("{}.{:06}", time.as_secs(), time.subsec_micros()),
+        TimestampFormat::EpochMs => time.as_millis().to_string(),
+        TimestampFormat::Iso => {
+            let secs = time.as_secs();
+            let (year, month, day) = civil_from_days(secs / 86400);
+            format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                year,
+                month,
+                day,
+                secs % 86400 / 3600,
+                secs % 3600 / 60,
+                secs % 60,
+                time.subsec_millis()
+            )
+        }
+    }
+}
+
+/// Converts the number of days since 1970-01-01 to a date of the Gregorian calendar.
+///
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let doe = days % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
 fn io_error_to_string(err: io::Error) -> String {
     format!("{}", err).to_lowercase()
 }
 
+/// Names keeps the names of the addresses which are looked up once per run.
+///
+/// The lookups run on separate threads so a slow DNS server doesn't hold the pings,
+/// a name shows up in the lines printed after its lookup completes.
+#[derive(Default)]
+struct Names(Arc<Mutex<HashMap<IpAddr, Option<Option<String>>>>>);
+
+impl Names {
+    /// Returns the name of the address, None if it's still being looked up.
+    ///
+    /// Addresses without a name are cached too so they aren't queried on each reply.
+    fn get(&self, addr: IpAddr) -> Option<Option<String>> {
+        // a PTR lookup of a loopback address says nothing new
+        if addr.is_loopback() {
+            return Some(Some(String::from("localhost")));
+        }
+
+        let mut names = self.0.lock().unwrap();
+        if let Some(name) = names.get(&addr) {
+            return name.clone();
+        }
+        names.insert(addr, None);
+        drop(names);
+
+        let names = self.0.clone();
+        smol::Task::blocking(async move {
+            let name = reverse_address(addr);
+            names.lock().unwrap().insert(addr, Some(name));
+        })
+        .detach();
+
+        None
+    }
+}
+
+/// Resolves the name again on a separate thread since the lookup is blocking.
+async fn reresolve(name: &str) -> Result<Vec<IpAddr>, AddressError> {
+    let name = name.to_owned();
+    smol::Task::blocking(async move { parse_address(&name) }).await
+}
+
+/// Looks up the name of the address, the lookup is blocking.
 fn reverse_address(addr: IpAddr) -> Option<String> {
     let resolver = Resolver::default().unwrap();
     let response = resolver.reverse_lookup(addr);
@@ -190,13 +1586,458 @@ fn reverse_address(addr: IpAddr) -> Option<String> {
     None
 }
 
-fn parse_address(addr: &str) -> Option<IpAddr> {
-    let resolver = Resolver::new(ResolverConfig::default(), ResolverOpts::default()).unwrap();
-    let response = resolver.lookup_ip(addr);
-    if let Ok(response) = response {
-        let addr = response.iter().next().unwrap();
-        return Some(addr);
+enum AddressError {
+    /// The name can't be resolved.
+    Unknown,
+    /// The name is resolved but there's no address associated with it.
+    NoAddress,
+}
+
+/// Returns the settings of pings to the address as the options describe them.
+///
+/// The capture file isn't set as only the pings to the target are recorded.
+fn ping_settings(
+    opts: &args::Opts,
+    addr: IpAddr,
+    read_timeout: Duration,
+    payload: Option<Vec<u8>>,
+) -> ping::Settings {
+    ping::Settings {
+        addr,
+        ttl: opts.ttl,
+        read_timeout,
+        pcap: None,
+        payload,
+        retries: opts.retries,
+        recv_buffer_size: opts.recv_buffer_size,
+        send_buffer_size: opts.send_buffer_size,
+        code: opts.code,
+        broadcast: opts.broadcast,
+        skip_checksum: opts.skip_checksum,
+        start_seq: opts.start_seq,
+        info_request: opts.info_request,
+        timestamp_payload: opts.timestamp_payload,
+        rate: opts.rate,
+        max_reply: opts.max_reply,
+        strict_rfc: opts.strict_rfc,
+        grow: opts.grow,
+        match_ident: opts.matching == Matching::Ident,
     }
+}
 
-    None
+/// Sends a request to each address in turn and returns the first one which replies.
+///
+/// It gives up once the deadline expires, each address is waited for
+/// the read timeout at most.
+fn failover<F>(
+    addresses: &[IpAddr],
+    deadline: Duration,
+    settings: F,
+    opts: &args::Opts,
+    out: &mut Printer,
+) -> io::Result<Option<IpAddr>>
+where
+    F: Fn(IpAddr) -> ping::Settings,
+{
+    let start = time::Instant::now();
+    for &addr in addresses {
+        let left = deadline.checked_sub(start.elapsed()).unwrap_or_default();
+        if left == Duration::from_secs(0) {
+            break;
+        }
+
+        let settings = settings(addr);
+        // a retried request would hold the address longer than the read timeout
+        let settings = ping::Settings {
+            read_timeout: settings.read_timeout.min(left),
+            retries: 0,
+            ..settings
+        };
+        let mut p = settings.build()?;
+        if let Ok(packet) = smol::run(p.run()) {
+            if PacketType::new(packet.icmp_type).map_or(false, |tp| tp.is_reply()) {
+                return Ok(Some(addr));
+            }
+        }
+
+        if opts.verbose {
+            output!(out, "PING: {} doesn't answer", addr);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Repeats a failed resolution up to the given number of times
+/// doubling the delay between attempts, so a DNS blip at start isn't fatal.
+///
+/// A name which is resolved without addresses isn't retried.
+fn retry_resolution<F>(
+    retries: usize,
+    backoff: Duration,
+    mut resolve: F,
+) -> Result<Vec<IpAddr>, AddressError>
+where
+    F: FnMut() -> Result<Vec<IpAddr>, AddressError>,
+{
+    let mut backoff = backoff;
+    let mut attempt = 0;
+    loop {
+        match resolve() {
+            Err(AddressError::Unknown) if attempt < retries => {
+                attempt += 1;
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Resolves the name to all of its addresses, the list is never empty.
+fn parse_address(addr: &str) -> Result<Vec<IpAddr>, AddressError> {
+    parse_address_with(addr, resolve_address)
+}
+
+/// Parses an ip address and falls back to the resolver only if it's not one,
+/// so bare addresses work on hosts without DNS configuration.
+fn parse_address_with<F>(addr: &str, resolve: F) -> Result<Vec<IpAddr>, AddressError>
+where
+    F: FnOnce(&str) -> Result<Vec<IpAddr>, AddressError>,
+{
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => Ok(vec![ping::unmap_address(ip)]),
+        Err(..) => resolve(addr),
+    }
+}
+
+fn resolve_address(addr: &str) -> Result<Vec<IpAddr>, AddressError> {
+    let resolver = match Resolver::new(ResolverConfig::default(), ResolverOpts::default()) {
+        Ok(resolver) => resolver,
+        Err(..) => return Err(AddressError::Unknown),
+    };
+    match resolver.lookup_ip(addr) {
+        Ok(response) => {
+            let addresses = response.iter().map(ping::unmap_address).collect::<Vec<_>>();
+            if addresses.is_empty() {
+                Err(AddressError::NoAddress)
+            } else {
+                Ok(addresses)
+            }
+        }
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => Err(AddressError::NoAddress),
+            _ => Err(AddressError::Unknown),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ip_address_without_resolver() {
+        let resolve = |_: &str| -> Result<Vec<IpAddr>, AddressError> {
+            panic!("the resolver must not be invoked")
+        };
+
+        let expected: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let addresses = parse_address_with("192.0.2.1", resolve);
+        assert!(matches!(addresses, Ok(addresses) if addresses == [expected]));
+
+        let addresses = parse_address_with("::ffff:192.0.2.1", resolve);
+        assert!(matches!(addresses, Ok(addresses) if addresses == [expected]));
+    }
+
+    #[test]
+    fn check_success_criteria() {
+        let mut rtt = RttStats::default();
+        rtt.push(Duration::from_millis(10));
+        rtt.push(Duration::from_millis(30));
+
+        assert!(violated_criteria(Some(25.0), Some(30), 4, 4, &rtt).is_empty());
+        assert!(violated_criteria(None, None, 4, 0, &RttStats::default()).is_empty());
+        assert_eq!(violated_criteria(Some(25.0), Some(30), 4, 3, &rtt).len(), 1);
+        assert_eq!(violated_criteria(Some(25.0), Some(20), 4, 3, &rtt).len(), 2);
+        assert_eq!(
+            violated_criteria(None, Some(20), 0, 0, &RttStats::default()).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn label_lines() {
+        let out = Printer {
+            label: Some(String::from("edge")),
+            ..Printer::default()
+        };
+        assert_eq!(
+            out.labeled("PING: socket reopened"),
+            "[edge] PING: socket reopened"
+        );
+        assert_eq!(out.labeled(r#"{"seq":1}"#), r#"{"label":"edge","seq":1}"#);
+        assert_eq!(out.labeled(""), "");
+        assert_eq!(Printer::default().labeled("PING"), "PING");
+    }
+
+    #[test]
+    fn loopback_name_without_lookup() {
+        let names = Names::default();
+        let addr = IpAddr::from([127, 0, 0, 1]);
+
+        let name = names.get(addr);
+        assert_eq!(name, Some(Some(String::from("localhost"))));
+        assert!(names.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replay_only_requests_from_capture() {
+        let source = Ipv4Addr::new(192, 0, 2, 1);
+        let destination = Ipv4Addr::new(192, 0, 2, 2);
+        let mut capture = Vec::new();
+        let mut writer = pcap::Writer::new(&mut capture).unwrap();
+        for &tp in &[8, 0, 3, 13, 14, 10, 15, 42, 43, 200] {
+            let mut icmp = [0; 64];
+            let size = IcmpBuilder::new()
+                .with_type(tp)
+                .with_ident(1)
+                .with_seq(2)
+                .build(&mut icmp)
+                .unwrap();
+            let mut datagram = [0; 128];
+            let size = IPV4Builder::new(64, ip::Protocol::ICMP, source, destination, &icmp[..size])
+                .build(&mut datagram)
+                .unwrap();
+            writer
+                .write_packet(SystemTime::now(), &datagram[..size])
+                .unwrap();
+        }
+
+        let requests = capture_requests(pcap::Reader::new(&capture[..]).unwrap()).unwrap();
+        let types = requests
+            .iter()
+            .map(|request| {
+                let mut buf = [0; 64];
+                let size = request.build(&mut buf).unwrap();
+                IcmpPacket::parse(&buf[..size]).unwrap().tp()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(types, [8, 13, 42]);
+    }
+
+    #[test]
+    fn join_waits_for_both_futures() {
+        let a = async {
+            smol::Timer::after(Duration::from_millis(20)).await;
+            1
+        };
+        let b = async { 2 };
+
+        let start = time::Instant::now();
+        assert_eq!(smol::block_on(join(a, b)), (1, 2));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn selftest_passes() {
+        assert_eq!(selftest(), Ok(()));
+    }
+
+    #[test]
+    fn retry_failed_resolution() {
+        let mut attempts = 0;
+        let addresses = retry_resolution(2, Duration::default(), || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(AddressError::Unknown),
+                _ => Ok(vec![IpAddr::from([192, 0, 2, 1])]),
+            }
+        });
+        assert!(addresses.is_ok());
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let addresses = retry_resolution(2, Duration::default(), || {
+            attempts += 1;
+            Err(AddressError::NoAddress)
+        });
+        assert!(matches!(addresses, Err(AddressError::NoAddress)));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn parse_name_with_resolver() {
+        let resolve = |_: &str| Err(AddressError::NoAddress);
+
+        let addresses = parse_address_with("example.invalid", resolve);
+        assert!(matches!(addresses, Err(AddressError::NoAddress)));
+    }
+
+    #[test]
+    fn rate_limited_loss() {
+        assert!(is_rate_limited(&[5, 10, 15, 20]));
+        // the sequence numbers wrap around
+        assert!(is_rate_limited(&[65530, 4, 14]));
+
+        assert!(!is_rate_limited(&[3, 7, 15, 16]));
+        // a burst of losses is more likely an outage
+        assert!(!is_rate_limited(&[1, 2, 3, 4]));
+
+        assert!(!is_rate_limited(&[5, 10]));
+        assert!(!is_rate_limited(&[]));
+    }
+
+    #[test]
+    fn smooth_rtt_by_rfc6298() {
+        let ms = Duration::from_millis;
+
+        let state = smooth_rtt(None, ms(100));
+        assert_eq!(state, (ms(100), ms(50)));
+
+        // srtt = 7/8 * 100 + 1/8 * 60, rttvar = 3/4 * 50 + 1/4 * |100 - 60|
+        let state = smooth_rtt(Some(state), ms(60));
+        assert_eq!(state, (ms(95), Duration::from_micros(47_500)));
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("edge"), r#""edge""#);
+        assert_eq!(json_string(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\logs"), r#""C:\\logs""#);
+        assert_eq!(json_string("a\nb\t\u{1b}"), r#""a\u000ab\u0009\u001b""#);
+    }
+
+    #[test]
+    fn packet_to_json() {
+        let info = PacketInfo {
+            ip_source_ip: IpAddr::from([192, 0, 2, 1]),
+            ip_destination_ip: IpAddr::from([192, 0, 2, 2]),
+            ip_ttl: 64,
+            icmp_seq: 7,
+            icmp_ident: 1,
+            icmp_type: 0,
+            icmp_code: 0,
+            received_bytes: 60,
+            icmp_payload_len: 32,
+            time: Duration::from_micros(1500),
+            retries: 0,
+            quoted: None,
+            payload_time: None,
+        };
+
+        assert_eq!(
+            packet_json(&info, Some("host \"a\"\\b")),
+            r#"{"seq":7,"source":"192.0.2.1","name":"host \"a\"\\b","ttl":64,"type":0,"code":0,"bytes":60,"time_ms":1.500}"#
+        );
+        assert_eq!(
+            packet_json(&info, None),
+            r#"{"seq":7,"source":"192.0.2.1","name":null,"ttl":64,"type":0,"code":0,"bytes":60,"time_ms":1.500}"#
+        );
+    }
+
+    #[test]
+    fn summary_to_json() {
+        let mut rtt = RttStats::default();
+        rtt.push(Duration::from_millis(10));
+        rtt.push(Duration::from_millis(30));
+        let mut audit = BTreeMap::new();
+        audit
+            .entry(IpAddr::from([192, 0, 2, 1]))
+            .or_insert_with(BTreeMap::new)
+            .insert((0, 0), 2);
+        let footer = Footer {
+            transmitted: 3,
+            received: 2,
+            time: Duration::from_secs(2),
+            rtt: &rtt,
+            rate_limited: false,
+            trimmed_mean: None,
+            srtt: None,
+            responders: None,
+            oversized: 0,
+            reconnections: 1,
+            sent_bytes: None,
+            downtime: Duration::default(),
+            audit: Some(&audit),
+        };
+
+        assert_eq!(
+            summary_json(&footer),
+            concat!(
+                r#"{"transmitted":3,"received":2,"time_ms":2000.000,"#,
+                r#""rtt_min_ms":10.000,"rtt_max_ms":30.000,"rtt_avg_ms":20.000,"#,
+                r#""reconnections":1,"audit":{"192.0.2.1":[{"type":0,"code":0,"count":2}]}}"#
+            )
+        );
+
+        let rtt = RttStats::default();
+        let footer = Footer {
+            transmitted: 1,
+            received: 0,
+            rtt: &rtt,
+            audit: None,
+            ..footer
+        };
+        assert_eq!(
+            summary_json(&footer),
+            r#"{"transmitted":1,"received":0,"time_ms":2000.000,"reconnections":1}"#
+        );
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11017), (2000, 3, 1));
+        assert_eq!(civil_from_days(10956), (1999, 12, 31));
+        assert_eq!(civil_from_days(10957), (2000, 1, 1));
+    }
+
+    #[test]
+    fn timestamp_formats() {
+        let at =
+            |secs, millis| UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis);
+
+        assert_eq!(
+            display_timestamp(UNIX_EPOCH, TimestampFormat::Iso),
+            "1970-01-01T00:00:00.000Z"
+        );
+        assert_eq!(
+            display_timestamp(at(951_782_401, 500), TimestampFormat::Iso),
+            "2000-02-29T00:00:01.500Z"
+        );
+        assert_eq!(
+            display_timestamp(at(946_684_799, 0), TimestampFormat::Iso),
+            "1999-12-31T23:59:59.000Z"
+        );
+        assert_eq!(
+            display_timestamp(at(946_684_800, 0), TimestampFormat::Iso),
+            "2000-01-01T00:00:00.000Z"
+        );
+        assert_eq!(
+            display_timestamp(at(946_684_799, 250), TimestampFormat::Unix),
+            "946684799.250000"
+        );
+        assert_eq!(
+            display_timestamp(at(946_684_799, 250), TimestampFormat::EpochMs),
+            "946684799250"
+        );
+    }
+
+    #[test]
+    fn hops_by_initial_ttl() {
+        assert_eq!(estimate_hops(64), 0);
+        assert_eq!(estimate_hops(54), 10);
+        assert_eq!(estimate_hops(128), 0);
+        assert_eq!(estimate_hops(120), 8);
+        assert_eq!(estimate_hops(255), 0);
+        assert_eq!(estimate_hops(250), 5);
+
+        // a ttl just above the initial value is counted from the next one
+        assert_eq!(estimate_hops(65), 63);
+        assert_eq!(estimate_hops(129), 126);
+        assert_eq!(estimate_hops(0), 64);
+    }
 }
@@ -1,4 +1,4 @@
-use super::{Builder, Packet, PacketError, Result};
+use super::{ip::IPV4Packet, Builder, Packet, PacketError, Result};
 
 pub struct IcmpPacket<'a>(&'a [u8]);
 
@@ -52,11 +52,14 @@ impl AsRef<[u8]> for IcmpPacket<'_> {
 /// PacketType is a representation of icmp messages types.
 ///
 /// It doesn't include deprecated types
+/// except information request and reply which legacy devices still answer
+/// and source quench which they still send.
 /// https://en.wikipedia.org/wiki/Internet_Control_Message_Protocol
 #[derive(Clone, Copy)]
 pub enum PacketType {
     EchoReply = 0,
     DestinationUnreachable = 3,
+    SourceQuench = 4,
     RedirectMessage = 5,
     EchoRequest = 8,
     RouterAdvertisement = 9,
@@ -65,6 +68,8 @@ pub enum PacketType {
     ParameterProblem = 12,
     Timestamp = 13,
     TimestampReply = 14,
+    InformationRequest = 15,
+    InformationReply = 16,
     ExtendedEchoRequest = 42,
     ExtendedEchoReply = 43,
 }
@@ -75,6 +80,7 @@ impl PacketType {
         [
             EchoReply,
             DestinationUnreachable,
+            SourceQuench,
             RedirectMessage,
             EchoRequest,
             RouterAdvertisement,
@@ -83,6 +89,8 @@ impl PacketType {
             ParameterProblem,
             Timestamp,
             TimestampReply,
+            InformationRequest,
+            InformationReply,
             ExtendedEchoRequest,
             ExtendedEchoReply,
         ]
@@ -90,6 +98,89 @@ impl PacketType {
         .find(|&&tt| t == tt as u8)
         .cloned()
     }
+
+    /// Checks whether the type is an error message
+    /// which quotes the datagram caused it.
+    pub fn is_error(&self) -> bool {
+        use PacketType::*;
+        match self {
+            DestinationUnreachable
+            | SourceQuench
+            | RedirectMessage
+            | TimeExceeded
+            | ParameterProblem => true,
+            _ => false,
+        }
+    }
+
+    /// Checks whether the type is a reply on a query.
+    pub fn is_reply(&self) -> bool {
+        use PacketType::*;
+        match self {
+            EchoReply | TimestampReply | InformationReply | ExtendedEchoReply => true,
+            _ => false,
+        }
+    }
+}
+
+/// Returns the description of the ICMP message type and code
+/// as they're named in the IANA registry.
+///
+/// https://www.iana.org/assignments/icmp-parameters/icmp-parameters.xhtml
+pub fn describe(tp: u8, code: u8) -> &'static str {
+    use PacketType::*;
+    let tp = match PacketType::new(tp) {
+        Some(tp) => tp,
+        None => return "nonstandard packet",
+    };
+
+    match (tp, code) {
+        (EchoReply, _) => "echo reply",
+        (DestinationUnreachable, 0) => "destination network unreachable",
+        (DestinationUnreachable, 1) => "destination host unreachable",
+        (DestinationUnreachable, 2) => "destination protocol unreachable",
+        (DestinationUnreachable, 3) => "destination port unreachable",
+        (DestinationUnreachable, 4) => "fragmentation needed and DF set",
+        (DestinationUnreachable, 5) => "source route failed",
+        (DestinationUnreachable, 6) => "destination network unknown",
+        (DestinationUnreachable, 7) => "destination host unknown",
+        (DestinationUnreachable, 8) => "source host isolated",
+        (DestinationUnreachable, 9) => "destination network administratively prohibited",
+        (DestinationUnreachable, 10) => "destination host administratively prohibited",
+        (DestinationUnreachable, 11) => "destination network unreachable for type of service",
+        (DestinationUnreachable, 12) => "destination host unreachable for type of service",
+        (DestinationUnreachable, 13) => "communication administratively prohibited",
+        (DestinationUnreachable, 14) => "host precedence violation",
+        (DestinationUnreachable, 15) => "precedence cutoff in effect",
+        (DestinationUnreachable, _) => "destination unreachable",
+        (SourceQuench, _) => "source quench (deprecated)",
+        (RedirectMessage, 0) => "redirect for the network",
+        (RedirectMessage, 1) => "redirect for the host",
+        (RedirectMessage, 2) => "redirect for the type of service and network",
+        (RedirectMessage, 3) => "redirect for the type of service and host",
+        (RedirectMessage, _) => "redirect message",
+        (EchoRequest, _) => "echo request",
+        (RouterAdvertisement, _) => "router advertisement",
+        (RouterSolicitation, _) => "router solicitation",
+        (TimeExceeded, 0) => "time to live exceeded in transit",
+        (TimeExceeded, 1) => "fragment reassembly time exceeded",
+        (TimeExceeded, _) => "time exceeded",
+        (ParameterProblem, 0) => "parameter problem: pointer indicates the error",
+        (ParameterProblem, 1) => "parameter problem: missing a required option",
+        (ParameterProblem, 2) => "parameter problem: bad length",
+        (ParameterProblem, _) => "parameter problem",
+        (Timestamp, _) => "timestamp",
+        (TimestampReply, _) => "timestamp reply",
+        (InformationRequest, _) => "information request",
+        (InformationReply, _) => "information reply",
+        (ExtendedEchoRequest, _) => "extended echo request",
+        (ExtendedEchoReply, 0) => "extended echo reply",
+        (ExtendedEchoReply, 1) => "extended echo reply: malformed query",
+        (ExtendedEchoReply, 2) => "extended echo reply: no such interface",
+        (ExtendedEchoReply, 3) => "extended echo reply: no such table entry",
+        (ExtendedEchoReply, 4) => "extended echo reply: multiple interfaces satisfy query",
+        (ExtendedEchoReply, _) => "extended echo reply",
+    }
 }
 
 const MINIMUM_HEADER_SIZE: usize = 8;
@@ -101,6 +192,8 @@ pub struct IcmpBuilder {
     pub seq: u16,
     pub ident: u16,
     pub payload: Option<Vec<u8>>,
+    /// Leaves the checksum zero so the kernel can fill it in.
+    pub skip_checksum: bool,
 }
 
 impl IcmpBuilder {
@@ -133,11 +226,27 @@ impl IcmpBuilder {
         self
     }
 
-    fn hint_size(&self) -> usize {
+    pub fn without_checksum(mut self) -> Self {
+        self.skip_checksum = true;
+        self
+    }
+
+    pub(crate) fn hint_size(&self) -> usize {
         MINIMUM_HEADER_SIZE + self.payload.as_ref().map_or(0, |p| p.len())
     }
 }
 
+impl From<&IcmpPacket<'_>> for IcmpBuilder {
+    fn from(packet: &IcmpPacket) -> Self {
+        IcmpBuilder::new()
+            .with_type(packet.tp())
+            .with_code(packet.code())
+            .with_seq(packet.seq())
+            .with_ident(packet.ident())
+            .with_payload(packet.payload())
+    }
+}
+
 impl Builder for IcmpBuilder {
     fn build(&self, buf: &mut [u8]) -> Result<usize> {
         if buf.len() < self.hint_size() {
@@ -159,6 +268,10 @@ impl Builder for IcmpBuilder {
         buf[2] = 0;
         buf[3] = 0;
 
+        if self.skip_checksum {
+            return Ok(self.hint_size());
+        }
+
         // we take only the affected part of the buffer to calculate
         // checksum without the bytes which are goes after.
         //
@@ -191,6 +304,11 @@ pub fn checksum(buf: &[u8]) -> u16 {
     !sum as u16
 }
 
+/// The type of ICMPv6 echo requests.
+///
+/// rfc4443 section 4.1
+pub const ECHO_REQUEST_V6: u8 = 128;
+
 pub struct EchoRequest;
 
 impl EchoRequest {
@@ -201,6 +319,164 @@ impl EchoRequest {
             .with_seq(seq)
             .with_ident(ident)
     }
+
+    /// Creates an ICMPv6 echo request.
+    ///
+    /// The message has the same layout as the ICMPv4 one,
+    /// the kernel computes its checksum over the pseudo header itself.
+    pub fn new_v6(ident: u16, seq: u16) -> IcmpBuilder {
+        Self::new(ident, seq).with_type(ECHO_REQUEST_V6)
+    }
+}
+
+/// InformationRequest builds a request a host answers with its network number.
+///
+/// The message has no payload, it's deprecated but legacy devices may still answer it.
+///
+/// rfc792 page 19
+pub struct InformationRequest;
+
+impl InformationRequest {
+    pub fn new(ident: u16, seq: u16) -> IcmpBuilder {
+        IcmpBuilder::new()
+            .with_type(PacketType::InformationRequest as u8)
+            .with_code(0)
+            .with_seq(seq)
+            .with_ident(ident)
+    }
+}
+
+/// The way an interface is identified in an extended echo request.
+///
+/// It's an Interface Identification Object from rfc-8335 section 2.1.
+pub enum InterfaceIdent<'a> {
+    Name(&'a str),
+    Index(u32),
+    Address(std::net::IpAddr),
+}
+
+impl InterfaceIdent<'_> {
+    const CLASS_NUM: u8 = 3;
+
+    fn c_type(&self) -> u8 {
+        match self {
+            InterfaceIdent::Name(..) => 1,
+            InterfaceIdent::Index(..) => 2,
+            InterfaceIdent::Address(..) => 3,
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        use std::net::IpAddr;
+        match self {
+            InterfaceIdent::Name(name) => {
+                let mut p = name.as_bytes().to_vec();
+                // the object must be padded by NULL characters to terminate on a 32-bit boundary
+                while p.len() % 4 != 0 {
+                    p.push(0);
+                }
+                p
+            }
+            InterfaceIdent::Index(index) => index.to_be_bytes().to_vec(),
+            InterfaceIdent::Address(addr) => {
+                let (afi, octets) = match addr {
+                    IpAddr::V4(addr) => (1u16, addr.octets().to_vec()),
+                    IpAddr::V6(addr) => (2u16, addr.octets().to_vec()),
+                };
+
+                let mut p = afi.to_be_bytes().to_vec();
+                p.push(octets.len() as u8);
+                p.push(0);
+                p.extend(octets);
+                p
+            }
+        }
+    }
+
+    /// Constructs an ICMP Extension Structure (rfc-4884) which carries this object.
+    fn extension(&self) -> Vec<u8> {
+        const EXTENSION_VERSION: u8 = 2;
+
+        let payload = self.payload();
+        let length = (4 + payload.len()) as u16;
+
+        let mut ext = vec![EXTENSION_VERSION << 4, 0, 0, 0];
+        ext.extend(&length.to_be_bytes());
+        ext.push(Self::CLASS_NUM);
+        ext.push(self.c_type());
+        ext.extend(payload);
+
+        let checksum = checksum(&ext);
+        ext[2] = (checksum >> 8) as u8;
+        ext[3] = checksum as u8;
+
+        ext
+    }
+}
+
+/// ExtendedEchoRequest constructs a PROBE request described in rfc-8335.
+///
+/// The sequence number of an extended echo is only 8 bits long,
+/// the last bit of the field is the L-bit which tells the probed node
+/// that the interface resides on it.
+pub struct ExtendedEchoRequest;
+
+impl ExtendedEchoRequest {
+    pub fn new(ident: u16, seq: u8, local: bool, interface: InterfaceIdent) -> IcmpBuilder {
+        IcmpBuilder::new()
+            .with_type(PacketType::ExtendedEchoRequest as u8)
+            .with_code(0)
+            .with_seq((u16::from(seq) << 8) + local as u16)
+            .with_ident(ident)
+            .with_payload(&interface.extension())
+    }
+}
+
+/// The state of a probed interface reported by an extended echo reply.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct InterfaceState {
+    /// The neighbor state, it's meaningful only for proxy interfaces.
+    pub state: u8,
+    pub active: bool,
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl IcmpPacket<'_> {
+    /// Parses the state bits of an extended echo reply.
+    ///
+    /// Returns None if the packet isn't an extended echo reply.
+    pub fn interface_state(&self) -> Option<InterfaceState> {
+        match PacketType::new(self.tp()) {
+            Some(PacketType::ExtendedEchoReply) => {
+                let bits = self.0[7];
+                Some(InterfaceState {
+                    state: bits >> 5,
+                    active: bits & 0b100 != 0,
+                    ipv4: bits & 0b010 != 0,
+                    ipv6: bits & 0b001 != 0,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<'a> IcmpPacket<'a> {
+    /// Parses the original datagram quoted in an error message.
+    ///
+    /// The quote contains the ip header and the first 8 bytes of the datagram,
+    /// which is enough to find the ICMP header of a request,
+    /// but the rest of the datagram is usually truncated.
+    ///
+    /// rfc792 page 4
+    pub fn quoted_packet(&self) -> Result<IPV4Packet<'a>> {
+        let buf: &'a [u8] = self.0;
+        match PacketType::new(self.tp()) {
+            Some(tp) if tp.is_error() => IPV4Packet::parse(&buf[8..]),
+            _ => Err(PacketError::WrongFormat),
+        }
+    }
 }
 
 mod tests {
@@ -231,6 +507,18 @@ mod tests {
         assert_eq!(expected, buf);
     }
 
+    #[test]
+    fn build_without_checksum() {
+        let mut buf = [0xff; 8];
+        let (expected, builder) = default_setup();
+        let res = builder.without_checksum().build(&mut buf);
+
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), 8);
+        assert_eq!(&buf[2..4], &[0, 0]);
+        assert_eq!(&buf[4..], &expected[4..]);
+    }
+
     #[test]
     fn build_in_small_buffer() {
         let mut buf = [0; 3];
@@ -240,6 +528,23 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn write_to() {
+        let (expected, builder) = default_setup();
+        let builder = builder.with_payload(&[1; 100]);
+
+        let mut buf = [0; 108];
+        let size = builder.build(&mut buf).unwrap();
+
+        let mut w = Vec::new();
+        let res = builder.write_to(&mut w);
+
+        assert!(res.is_ok());
+        assert_eq!(res.unwrap(), size);
+        assert_eq!(&w[..], &buf[..]);
+        assert_eq!(&w[..2], &expected[..2]);
+    }
+
     #[test]
     fn parse() {
         let (buf, buffer) = default_setup();
@@ -276,6 +581,32 @@ mod tests {
         assert!(!packet.unwrap().is_checksum_correct());
     }
 
+    #[test]
+    fn build_echo_request_v6() {
+        let mut buf = [0; 8];
+        let size = EchoRequest::new_v6(1, 2).build(&mut buf).unwrap();
+
+        assert_eq!(size, 8);
+        assert_eq!(buf[0], 128);
+        assert_eq!(buf[1], 0);
+        assert_eq!(&buf[4..], &[0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn echo_request_checksum() {
+        // the payload has an odd length so the last byte is padded
+        let builder = EchoRequest::new(0x1234, 7).with_payload(b"hello, checksum");
+        let mut buf = vec![0; builder.hint_size()];
+        let size = builder.build(&mut buf).unwrap();
+
+        let packet = IcmpPacket::parse(&buf[..size]).unwrap();
+        assert!(packet.is_checksum_correct());
+
+        buf[size - 1] ^= 0xff;
+        let packet = IcmpPacket::parse(&buf[..size]).unwrap();
+        assert!(!packet.is_checksum_correct());
+    }
+
     #[test]
     fn checksum() {
         let buffer = [0, 0, 0, 1, 2, 3, 4];
@@ -284,6 +615,128 @@ mod tests {
         assert_eq!(65015, sum);
     }
 
+    #[test]
+    fn build_extended_echo_request() {
+        let mut buf = [0; 64];
+        let builder = ExtendedEchoRequest::new(2020, 24, true, InterfaceIdent::Name("eth0"));
+        let size = builder.build(&mut buf).unwrap();
+
+        assert_eq!(size, 8 + 4 + 4 + 4);
+        assert_eq!(buf[0], PacketType::ExtendedEchoRequest as u8);
+        assert_eq!(&buf[6..8], &[24, 1]);
+        assert_eq!(&buf[8..10], &[0x20, 0]);
+        assert_eq!(super::checksum(&buf[8..size]), 0);
+        assert_eq!(&buf[12..16], &[0, 8, 3, 1]);
+        assert_eq!(&buf[16..size], b"eth0");
+
+        let packet = IcmpPacket::parse(&buf[..size]).unwrap();
+        assert!(packet.is_checksum_correct());
+    }
+
+    #[test]
+    fn build_extended_echo_request_by_address() {
+        let mut buf = [0; 64];
+        let addr = std::net::Ipv4Addr::new(192, 168, 0, 1);
+        let builder = ExtendedEchoRequest::new(1, 1, false, InterfaceIdent::Address(addr.into()));
+        let size = builder.build(&mut buf).unwrap();
+
+        assert_eq!(&buf[6..8], &[1, 0]);
+        assert_eq!(&buf[12..16], &[0, 12, 3, 3]);
+        assert_eq!(&buf[16..size], &[0, 1, 4, 0, 192, 168, 0, 1]);
+    }
+
+    #[test]
+    fn parse_interface_state() {
+        let tp = PacketType::ExtendedEchoReply as u8;
+        let mut buf = vec![tp, 0, 0, 0, 7, 228, 24, 0b0100_0101];
+        let packet = IcmpPacket::parse(&buf).unwrap();
+
+        let state = packet.interface_state();
+        assert_eq!(
+            state,
+            Some(InterfaceState {
+                state: 2,
+                active: true,
+                ipv4: false,
+                ipv6: true,
+            })
+        );
+
+        buf[0] = PacketType::EchoReply as u8;
+        let packet = IcmpPacket::parse(&buf).unwrap();
+        assert!(packet.interface_state().is_none());
+    }
+
+    #[test]
+    fn packet_type_classification() {
+        assert!(PacketType::TimeExceeded.is_error());
+        assert!(PacketType::new(4).map_or(false, |tp| tp.is_error()));
+        assert!(PacketType::DestinationUnreachable.is_error());
+        assert!(!PacketType::TimeExceeded.is_reply());
+        assert!(PacketType::EchoReply.is_reply());
+        assert!(PacketType::TimestampReply.is_reply());
+        assert!(PacketType::InformationReply.is_reply());
+        assert!(!PacketType::InformationRequest.is_reply());
+        assert!(!PacketType::EchoReply.is_error());
+        assert!(!PacketType::EchoRequest.is_reply());
+        assert!(!PacketType::EchoRequest.is_error());
+    }
+
+    #[test]
+    fn describe() {
+        assert_eq!(super::describe(0, 0), "echo reply");
+        assert_eq!(super::describe(3, 3), "destination port unreachable");
+        assert_eq!(super::describe(3, 200), "destination unreachable");
+        assert_eq!(super::describe(12, 2), "parameter problem: bad length");
+        assert_eq!(super::describe(11, 0), "time to live exceeded in transit");
+        assert_eq!(super::describe(11, 1), "fragment reassembly time exceeded");
+        assert_eq!(super::describe(16, 0), "information reply");
+        assert_eq!(super::describe(4, 0), "source quench (deprecated)");
+        assert_eq!(super::describe(200, 0), "nonstandard packet");
+    }
+
+    #[test]
+    fn quoted_packet() {
+        use crate::packet::ip::{IPV4Builder, Protocol};
+        use std::net::Ipv4Addr;
+
+        let mut request = [0; 8];
+        EchoRequest::new(1, 2).build(&mut request).unwrap();
+        let mut quote = [0; 28];
+        let destination = Ipv4Addr::new(10, 0, 0, 1);
+        IPV4Builder::new(
+            1,
+            Protocol::ICMP,
+            Ipv4Addr::LOCALHOST,
+            destination,
+            &request,
+        )
+        .build(&mut quote)
+        .unwrap();
+        let mut buf = [0; 36];
+        IcmpBuilder::new()
+            .with_type(PacketType::DestinationUnreachable as u8)
+            .with_payload(&quote)
+            .build(&mut buf)
+            .unwrap();
+
+        let packet = IcmpPacket::parse(&buf).unwrap();
+        let quoted = packet.quoted_packet();
+
+        assert!(quoted.is_ok());
+        let quoted = quoted.unwrap();
+        assert_eq!(quoted.destination_ip(), destination);
+        assert_eq!(quoted.protocol(), Protocol::ICMP as u8);
+        let icmp = IcmpPacket::parse(quoted.payload().unwrap()).unwrap();
+        assert_eq!(icmp.seq(), 2);
+
+        let truncated = IcmpPacket::parse(&buf[..20]).unwrap();
+        assert!(truncated.quoted_packet().is_err());
+
+        let reply = IcmpPacket::parse(&request).unwrap();
+        assert!(reply.quoted_packet().is_err());
+    }
+
     fn default_setup() -> (Vec<u8>, IcmpBuilder) {
         let buffer = vec![20, 0, 228, 3, 7, 228, 0, 24];
         let builder = IcmpBuilder::new()
@@ -23,6 +23,15 @@ impl IPV4Packet<'_> {
         Ipv4Addr::new(self.buf[16], self.buf[17], self.buf[18], self.buf[19])
     }
 
+    /// Returns the options between the fixed header and the payload.
+    pub fn options(&self) -> &[u8] {
+        let size = 4 * (self.buf[0] & 0x0f) as usize;
+        match size {
+            size if size > MINIMUM_HEADER_SIZE => &self.buf[MINIMUM_HEADER_SIZE..size],
+            _ => &[],
+        }
+    }
+
     pub fn payload(&self) -> Option<&[u8]> {
         let size = 4 * (self.buf[0] & 0x0f) as usize;
         match size {
@@ -51,6 +60,7 @@ impl Protocol {
 
 const IPV4_VERSION: u8 = 4;
 const MINIMUM_HEADER_SIZE: usize = 20;
+const MAXIMUM_HEADER_SIZE: usize = 60;
 
 impl<'a> Packet<'a> for IPV4Packet<'a> {
     type Builder = IPV4Builder<'a>;
@@ -82,6 +92,7 @@ pub struct IPV4Builder<'a> {
     protocol: Protocol,
     source: Ipv4Addr,
     dst: Ipv4Addr,
+    options: &'a [u8],
     payload: &'a [u8],
 }
 
@@ -92,16 +103,28 @@ impl<'a> IPV4Builder<'a> {
             protocol: p,
             dst,
             source,
+            options: &[],
             payload,
         }
     }
+
+    /// Sets the options which are written after the fixed header.
+    ///
+    /// They're padded by zeros (end of option list) to a multiple of 4 bytes.
+    pub fn with_options(mut self, options: &'a [u8]) -> Self {
+        self.options = options;
+        self
+    }
 }
 
 impl Builder for IPV4Builder<'_> {
     fn build(&self, buf: &mut [u8]) -> Result<usize> {
-        use std::io::Write;
+        // the options are padded to the 32-bit words the header length is counted in
+        let header_size = MINIMUM_HEADER_SIZE + ((self.options.len() + 3) & !3);
+        if header_size > MAXIMUM_HEADER_SIZE {
+            return Err(PacketError::InvalidHeaderSize);
+        }
 
-        let header_size = 20;
         let size = header_size + self.payload.len();
         if buf.len() < size {
             return Err(PacketError::InvalidBufferSize);
@@ -111,15 +134,17 @@ impl Builder for IPV4Builder<'_> {
 
         buf[0] = (4 << 4) + (header_size / 4) as u8;
 
-        buf[2] = (size << 8) as u8;
+        buf[2] = (size >> 8) as u8;
         buf[3] = size as u8;
 
         buf[8] = self.ttl;
         buf[9] = self.protocol as u8;
 
-        (&mut buf[12..]).write(&self.source.octets()).unwrap();
-        (&mut buf[16..]).write(&self.dst.octets()).unwrap();
-        (&mut buf[header_size..]).write(self.payload).unwrap();
+        let options_end = MINIMUM_HEADER_SIZE + self.options.len();
+        buf[12..16].copy_from_slice(&self.source.octets());
+        buf[16..20].copy_from_slice(&self.dst.octets());
+        buf[MINIMUM_HEADER_SIZE..options_end].copy_from_slice(self.options);
+        buf[header_size..size].copy_from_slice(self.payload);
 
         Ok(size)
     }
@@ -195,6 +220,43 @@ mod tests {
         assert_eq!(ip.payload(), expected.payload());
     }
 
+    #[test]
+    fn build_with_options() {
+        // a record route option with room for a single address
+        let options = [7, 7, 4, 0, 0, 0, 0];
+        let payload = [1, 2, 3, 4];
+
+        let mut buf = [0; 1024];
+        let size = IPV4Builder::new(
+            64,
+            Protocol::ICMP,
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::LOCALHOST,
+            &payload,
+        )
+        .with_options(&options)
+        .build(&mut buf)
+        .unwrap();
+
+        assert_eq!(size, 20 + 8 + payload.len());
+
+        let ip = IPV4Packet::parse(&buf[..size]).unwrap();
+        assert_eq!(ip.options(), &[7, 7, 4, 0, 0, 0, 0, 0]);
+        assert_eq!(ip.payload(), Some(&payload[..]));
+        assert_eq!(ip.ttl(), 64);
+
+        let options = [1; 41];
+        let ip = IPV4Builder::new(
+            64,
+            Protocol::ICMP,
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::LOCALHOST,
+            &payload,
+        )
+        .with_options(&options);
+        assert!(ip.build(&mut buf).is_err());
+    }
+
     fn setup<'a>() -> (Vec<u8>, IPV4Packet<'a>) {
         let b: &'static [u8] = &[
             64, 0, 0, 60, 35, 24, 0, 0, 56, 1, 230, 134, 127, 0, 0, 1, 192, 168, 100, 10,
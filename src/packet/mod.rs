@@ -45,8 +45,36 @@ pub trait Builder {
     ///
     /// Returns the amount of bytes were written in the buffer.
     fn build(&self, _: &mut [u8]) -> Result<usize>;
+
+    /// Construct the bytes representation of this packet and write it to the writer.
+    ///
+    /// The packet is built in a temporary buffer which grows
+    /// until the packet fits in it.
+    ///
+    /// Returns the amount of bytes were written in the writer.
+    fn write_to<W: std::io::Write>(&self, w: &mut W) -> Result<usize>
+    where
+        Self: Sized,
+    {
+        let mut buf = vec![0; 64];
+        loop {
+            match self.build(&mut buf) {
+                Ok(size) => {
+                    w.write_all(&buf[..size])?;
+                    break Ok(size);
+                }
+                Err(PacketError::InvalidBufferSize) if buf.len() < MAXIMUM_PACKET_SIZE => {
+                    buf.resize(buf.len() * 2, 0)
+                }
+                Err(err) => break Err(err),
+            }
+        }
+    }
 }
 
+/// The largest packet can be transmitted by IP.
+const MAXIMUM_PACKET_SIZE: usize = 65535;
+
 /// Packet trait is responsible only for rendering the packet.
 ///
 /// The good example of this trait is [`IcmpHeader`].
@@ -0,0 +1,227 @@
+//! This module contains a minimal writer and reader of the libpcap file format.
+//!
+//! The records are raw IP datagrams (LINKTYPE_RAW)
+//! so the captures can be opened by tools like Wireshark or tcpdump.
+
+use std::{
+    convert::TryInto,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOSECONDS: u32 = 0xa1b2_3c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_IPV4: u32 = 228;
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+pub struct Writer<W: io::Write> {
+    w: W,
+}
+
+impl<W: io::Write> Writer<W> {
+    /// Creates a writer and writes the global header of the file.
+    pub fn new(mut w: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(&MAGIC.to_le_bytes());
+        header.extend(&VERSION_MAJOR.to_le_bytes());
+        header.extend(&VERSION_MINOR.to_le_bytes());
+        // timezone offset and timestamps accuracy are always 0
+        header.extend(&0i32.to_le_bytes());
+        header.extend(&0u32.to_le_bytes());
+        header.extend(&SNAPLEN.to_le_bytes());
+        header.extend(&LINKTYPE_RAW.to_le_bytes());
+
+        w.write_all(&header)?;
+
+        Ok(Self { w })
+    }
+
+    /// Writes a packet record captured at the given time.
+    pub fn write_packet(&mut self, time: SystemTime, packet: &[u8]) -> io::Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let length = packet.len() as u32;
+
+        let mut header = Vec::with_capacity(16);
+        header.extend(&(time.as_secs() as u32).to_le_bytes());
+        header.extend(&time.subsec_micros().to_le_bytes());
+        header.extend(&length.to_le_bytes());
+        header.extend(&length.to_le_bytes());
+
+        self.w.write_all(&header)?;
+        self.w.write_all(packet)?;
+        self.w.flush()
+    }
+}
+
+/// Reader reads IP datagrams from a capture.
+///
+/// It supports raw ip and ethernet captures,
+/// the link layer header of ethernet frames is stripped.
+pub struct Reader<R: io::Read> {
+    r: R,
+    big_endian: bool,
+    linktype: u32,
+    snaplen: u32,
+}
+
+impl<R: io::Read> Reader<R> {
+    /// Creates a reader and checks the global header of the file.
+    pub fn new(mut r: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        r.read_exact(&mut header)?;
+
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let big_endian = match magic {
+            MAGIC | MAGIC_NANOSECONDS => false,
+            _ if magic.swap_bytes() == MAGIC || magic.swap_bytes() == MAGIC_NANOSECONDS => true,
+            _ => return Err(invalid_data("not a pcap file")),
+        };
+
+        let mut reader = Self {
+            r,
+            big_endian,
+            linktype: 0,
+            snaplen: 0,
+        };
+        reader.snaplen = reader.u32(&header[16..]);
+        reader.linktype = reader.u32(&header[20..]);
+        match reader.linktype {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_ETHERNET => Ok(reader),
+            _ => Err(invalid_data("unsupported link type")),
+        }
+    }
+
+    /// Reads the next IP datagram.
+    ///
+    /// Returns None when the end of the capture is reached.
+    pub fn next_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let mut header = [0; 16];
+            match self.r.read_exact(&mut header) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            }
+
+            // a corrupted length mustn't make us allocate gigabytes
+            let length = self.u32(&header[8..]);
+            if length > self.snaplen || length > SNAPLEN {
+                return Err(invalid_data("packet exceeds the snapshot length"));
+            }
+
+            let length = length as usize;
+            let mut packet = vec![0; length];
+            self.r.read_exact(&mut packet)?;
+
+            if self.linktype != LINKTYPE_ETHERNET {
+                return Ok(Some(packet));
+            }
+
+            // we're interested only in ipv4 frames
+            if packet.len() > ETHERNET_HEADER_SIZE && packet[12..14] == [0x08, 0x00] {
+                return Ok(Some(packet.split_off(ETHERNET_HEADER_SIZE)));
+            }
+        }
+    }
+
+    fn u32(&self, buf: &[u8]) -> u32 {
+        let bytes = buf[..4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn global_header() {
+        let w = Writer::new(Vec::new()).unwrap();
+
+        assert_eq!(
+            w.w,
+            vec![
+                0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 0, 0, 101, 0,
+                0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn write_packet() {
+        let mut w = Writer::new(Vec::new()).unwrap();
+        let time = UNIX_EPOCH + Duration::from_micros(1_000_002);
+
+        let res = w.write_packet(time, &[1, 2, 3]);
+
+        assert!(res.is_ok());
+        assert_eq!(
+            &w.w[24..],
+            &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn read_written_packets() {
+        let mut w = Writer::new(Vec::new()).unwrap();
+        w.write_packet(SystemTime::now(), &[1, 2, 3]).unwrap();
+        w.write_packet(SystemTime::now(), &[4, 5]).unwrap();
+
+        let mut r = Reader::new(w.w.as_slice()).unwrap();
+
+        assert_eq!(r.next_packet().unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(r.next_packet().unwrap(), Some(vec![4, 5]));
+        assert_eq!(r.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn read_ethernet_frames() {
+        let mut capture = Writer::new(Vec::new()).unwrap().w;
+        capture[20] = LINKTYPE_ETHERNET as u8;
+
+        let mut w = Writer { w: capture };
+        let mut arp = vec![0; 12];
+        arp.extend(&[0x08, 0x06, 1]);
+        let mut ip = vec![0; 12];
+        ip.extend(&[0x08, 0x00, 1, 2]);
+        w.write_packet(SystemTime::now(), &arp).unwrap();
+        w.write_packet(SystemTime::now(), &ip).unwrap();
+
+        let mut r = Reader::new(w.w.as_slice()).unwrap();
+
+        assert_eq!(r.next_packet().unwrap(), Some(vec![1, 2]));
+        assert_eq!(r.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn read_oversized_packet() {
+        let mut w = Writer::new(Vec::new()).unwrap();
+        w.write_packet(SystemTime::now(), &[1, 2, 3]).unwrap();
+        w.w[24 + 8..24 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut r = Reader::new(w.w.as_slice()).unwrap();
+
+        assert!(r.next_packet().is_err());
+    }
+
+    #[test]
+    fn read_not_pcap() {
+        let r = Reader::new([0u8; 24].as_ref());
+
+        assert!(r.is_err());
+    }
+}
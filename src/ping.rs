@@ -1,17 +1,30 @@
-use crate::packet::{
-    icmp::{self, IcmpBuilder, IcmpPacket, PacketType},
-    ip::IPV4Packet,
-    Builder, Packet, PacketError,
+use crate::{
+    packet::{
+        icmp::{self, IcmpBuilder, IcmpPacket, PacketType},
+        ip::{self, IPV4Builder, IPV4Packet},
+        Builder, Packet, PacketError,
+    },
+    pcap,
+    stats::{RttStats, Summary},
 };
 use async_trait::async_trait;
 use socket2::{Domain, Protocol, Type};
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    fs,
+    future::Future,
     io, net,
-    time::{self, Duration},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{self, Duration, SystemTime},
 };
 
 pub const DATA_SIZE: usize = 32;
 
+/// The largest payload fits in an ipv4 datagram with ICMP header.
+pub const MAXIMUM_PAYLOAD_SIZE: usize = 65535 - 20 - 8;
+
 pub type Result<T> = std::result::Result<T, PingError>;
 
 #[derive(Debug)]
@@ -19,6 +32,44 @@ pub enum PingError {
     PacketError(PacketError),
     Send(io::Error),
     Recv(io::Error),
+    Record(io::Error),
+    /// No reply on the request with the sequence number has arrived in time.
+    Timeout(u16),
+}
+
+/// PingEvent is the outcome of a request classified
+/// so consumers can tell replies from failures without matching strings.
+pub enum PingEvent {
+    /// An echo or information reply.
+    Reply(PacketInfo),
+    /// A message sent in response to a request which isn't a reply,
+    /// e.g. time exceeded or destination unreachable.
+    NonEcho(PacketInfo),
+    /// No reply on the request with the sequence number has arrived in time.
+    Timeout {
+        seq: u16,
+    },
+    SendError(io::Error),
+    RecvError(io::Error),
+    /// Any other failure, e.g. the packet can't be recorded.
+    Error(PingError),
+}
+
+impl From<Result<PacketInfo>> for PingEvent {
+    fn from(result: Result<PacketInfo>) -> Self {
+        match result {
+            Ok(info) => match PacketType::new(info.icmp_type) {
+                Some(PacketType::EchoReply) | Some(PacketType::InformationReply) => {
+                    Self::Reply(info)
+                }
+                _ => Self::NonEcho(info),
+            },
+            Err(PingError::Timeout(seq)) => Self::Timeout { seq },
+            Err(PingError::Send(err)) => Self::SendError(err),
+            Err(PingError::Recv(err)) => Self::RecvError(err),
+            Err(err) => Self::Error(err),
+        }
+    }
 }
 
 impl From<PacketError> for PingError {
@@ -29,90 +80,838 @@ impl From<PacketError> for PingError {
 
 pub struct PacketInfo {
     pub ip_source_ip: net::IpAddr,
+    pub ip_destination_ip: net::IpAddr,
     pub ip_ttl: u8,
     pub icmp_seq: u16,
+    pub icmp_ident: u16,
     pub icmp_type: u8,
+    pub icmp_code: u8,
     pub received_bytes: usize,
+    /// The size of the ICMP payload which may differ from the sent one
+    /// if a middlebox pads or trims it.
+    pub icmp_payload_len: usize,
     pub time: Duration,
+    /// The number of times the request was resent before the reply arrived.
+    pub retries: usize,
+    /// The datagram quoted by an error message.
+    pub quoted: Option<QuotedPacket>,
+    /// The rtt computed from the timestamp echoed in the payload.
+    pub payload_time: Option<Duration>,
+}
+
+/// The details of the datagram which caused an error message.
+pub struct QuotedPacket {
+    pub destination: net::Ipv4Addr,
+    pub protocol: u8,
 }
 
 pub struct Settings {
     pub addr: net::IpAddr,
     pub ttl: Option<u32>,
     pub read_timeout: Duration,
+    /// A file where sent and received packets are recorded in pcap format.
+    pub pcap: Option<fs::File>,
+    /// The payload of echo requests, a random one is used if it's not set.
+    pub payload: Option<Vec<u8>>,
+    /// The number of times a request is resent if no reply arrives.
+    pub retries: usize,
+    /// The size of the socket receive buffer (SO_RCVBUF).
+    pub recv_buffer_size: Option<usize>,
+    /// The size of the socket send buffer (SO_SNDBUF).
+    pub send_buffer_size: Option<usize>,
+    /// The code of echo requests, it's 0 for standard ones.
+    pub code: u8,
+    /// Allows to send requests to a broadcast address (SO_BROADCAST).
+    pub broadcast: bool,
+    /// Leaves the checksum of requests zero for the kernel to compute it.
+    pub skip_checksum: bool,
+    /// The sequence number of the first request.
+    pub start_seq: u16,
+    /// Sends information requests instead of echo requests.
+    pub info_request: bool,
+    /// Writes a timestamp into the leading bytes of the payload of each request.
+    pub timestamp_payload: bool,
+    /// The maximum number of requests sent per second including resent ones.
+    pub rate: Option<f64>,
+    /// The size of the largest accepted reply in bytes including the ip header.
+    pub max_reply: Option<usize>,
+    /// Rejects time exceeded messages which don't quote the whole payload.
+    pub strict_rfc: bool,
+    /// The step the payload grows by after each request and the size it stops at.
+    pub grow: Option<(usize, usize)>,
+    /// Accepts echo replies with our identifier even if their payload differs.
+    pub match_ident: bool,
 }
 
 impl Settings {
-    pub fn build(self) -> Ping<Socket2> {
-        let sock =
-            socket2::Socket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4())).unwrap();
-        sock.set_nonblocking(true).unwrap();
-        sock.set_read_timeout(Some(self.read_timeout)).unwrap();
+    /// Creates a raw socket and configures a pinger on top of it.
+    ///
+    /// Creation of a raw socket requires privileges,
+    /// without them an error of PermissionDenied kind is returned.
+    pub fn build(self) -> io::Result<Ping<Socket2>> {
+        let destination = unmap_address(self.addr);
+        let options = SocketOptions {
+            addr: net::SocketAddr::new(destination, 0),
+            ttl: self.ttl,
+            read_timeout: self.read_timeout,
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+            broadcast: self.broadcast,
+        };
+        let sock = options.open()?;
+        let mut ping = Ping::new(sock);
+        ping.opener = Some(Box::new(move || options.open()));
+        ping.timeout = self.read_timeout;
+        ping.retries = self.retries;
+        ping.timestamp_payload = self.timestamp_payload;
+        ping.limiter = self.rate.map(TokenBucket::new);
+        ping.max_reply = self.max_reply;
+        ping.strict_rfc = self.strict_rfc;
+        ping.grow = self.grow;
+        ping.match_ident = self.match_ident;
+        if destination.is_ipv6() {
+            let payload = ping.req.payload.take();
+            ping.req = icmp::EchoRequest::new_v6(ping.req.ident, 0);
+            ping.req.payload = payload;
+        }
+        if self.info_request {
+            ping.req = icmp::InformationRequest::new(ping.req.ident, 0);
+        }
+        ping.req.code = self.code;
+        ping.req.skip_checksum = self.skip_checksum;
+        ping.start_from(self.start_seq);
+        if let (Some(payload), false) = (self.payload, self.info_request) {
+            ping.req.payload = Some(payload);
+        }
+
+        // pcap records are raw ip datagrams so we can record only ipv4 for now
+        if let (Some(file), net::IpAddr::V4(destination)) = (self.pcap, destination) {
+            let ttl = self.ttl.map_or(DEFAULT_TTL, |ttl| ttl as u8);
+            ping.recorder = Some(Recorder::new(file, destination, ttl)?);
+        }
+
+        Ok(ping)
+    }
+}
+
+/// SettingsBuilder creates settings with the defaults
+/// so only the options which differ from them have to be set.
+pub struct SettingsBuilder {
+    settings: Settings,
+}
+
+impl SettingsBuilder {
+    pub fn new(addr: net::IpAddr) -> Self {
+        Self {
+            settings: Settings {
+                addr,
+                ttl: None,
+                read_timeout: DEFAULT_TIMEOUT,
+                pcap: None,
+                payload: None,
+                retries: 0,
+                recv_buffer_size: None,
+                send_buffer_size: None,
+                code: 0,
+                broadcast: false,
+                skip_checksum: false,
+                start_seq: 1,
+                info_request: false,
+                timestamp_payload: false,
+                rate: None,
+                max_reply: None,
+                strict_rfc: false,
+                grow: None,
+                match_ident: false,
+            },
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.settings.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.read_timeout = timeout;
+        self
+    }
+
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.settings.payload = Some(payload);
+        self
+    }
+
+    /// Sets a random payload of the size.
+    pub fn with_payload_size(self, size: usize) -> Self {
+        self.with_payload(uniq_payload(size))
+    }
+
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.settings.retries = retries;
+        self
+    }
+
+    pub fn with_start_seq(mut self, seq: u16) -> Self {
+        self.settings.start_seq = seq;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: f64) -> Self {
+        self.settings.rate = Some(rate);
+        self
+    }
+
+    pub fn with_pcap(mut self, file: fs::File) -> Self {
+        self.settings.pcap = Some(file);
+        self
+    }
+
+    pub fn build(self) -> Settings {
+        self.settings
+    }
+}
+
+/// The options of a socket which are kept to reopen it.
+struct SocketOptions {
+    addr: net::SocketAddr,
+    ttl: Option<u32>,
+    read_timeout: Duration,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    broadcast: bool,
+}
+
+impl SocketOptions {
+    fn open(&self) -> io::Result<Socket2> {
+        let sock = socket2::Socket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4()))?;
+        sock.set_nonblocking(true)?;
+        sock.set_read_timeout(Some(self.read_timeout))?;
         if let Some(ttl) = self.ttl {
-            sock.set_ttl(ttl).unwrap();
+            sock.set_ttl(ttl)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            sock.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            sock.set_send_buffer_size(size)?;
+        }
+        if self.broadcast {
+            sock.set_broadcast(true)?;
         }
 
-        let addr = std::net::SocketAddr::new(self.addr, 0);
-        let sock = Socket2::new(sock, addr);
-        Ping::new(sock)
+        Socket2::new(sock, self.addr)
     }
 }
 
+const MAXIMUM_IP_HEADER_SIZE: usize = 60;
+/// The size of the receive buffer unless the maximum reply size is set,
+/// it fits error messages which quote a datagram with the largest ip header.
+const DEFAULT_BUFFER_SIZE: usize = 300;
+
+/// The number of times a send is repeated if the socket is temporarily out of buffers.
+const SEND_RETRIES: u32 = 3;
+const SEND_BACKOFF: Duration = Duration::from_millis(1);
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Linux's default TTL which is used in recorded requests if it's not set explicitly.
+const DEFAULT_TTL: u8 = 64;
+/// The size of the timestamp written into the payload, microseconds since the start.
+const TIMESTAMP_SIZE: usize = 8;
+
 pub struct Ping<S: Socket> {
     sock: S,
     req: IcmpBuilder,
+    recorder: Option<Recorder>,
+    replay: VecDeque<IcmpBuilder>,
+    /// Whether the requests are taken from the replayed packets.
+    replayed: bool,
+    timeout: Duration,
+    retries: usize,
+    /// The requests of the last call which haven't got a reply in time
+    /// with the time they were sent.
+    timed_out: HashMap<u16, time::Instant>,
+    /// Creates a socket the same way the current one was created.
+    opener: Option<Box<dyn Fn() -> io::Result<S>>>,
+    timestamp_payload: bool,
+    /// The time payload timestamps are counted from.
+    epoch: time::Instant,
+    limiter: Option<TokenBucket>,
+    max_reply: Option<usize>,
+    strict_rfc: bool,
+    grow: Option<(usize, usize)>,
+    match_ident: bool,
+    /// The number of replies discarded because they exceed the maximum size.
+    oversized: usize,
+    sent: usize,
+    received: usize,
+    rtt: RttStats,
 }
 
 impl<S: Socket> Ping<S> {
     fn new(sock: S) -> Self {
-        let payload = uniq_payload();
+        let payload = uniq_payload(DATA_SIZE);
         let req = icmp::EchoRequest::new(uniq_ident(), 0).with_payload(&payload);
 
-        Self { req, sock }
+        Self {
+            req,
+            sock,
+            recorder: None,
+            replay: VecDeque::new(),
+            replayed: false,
+            timeout: DEFAULT_TIMEOUT,
+            retries: 0,
+            timed_out: HashMap::new(),
+            opener: None,
+            timestamp_payload: false,
+            epoch: time::Instant::now(),
+            limiter: None,
+            max_reply: None,
+            strict_rfc: false,
+            grow: None,
+            match_ident: false,
+            oversized: 0,
+            sent: 0,
+            received: 0,
+            rtt: RttStats::default(),
+        }
     }
 
     pub async fn run(&mut self) -> Result<PacketInfo> {
-        let mut buf = vec![0; 300];
-        self.req.seq += 1;
+        self.timed_out.clear();
+        self.next_request();
+
+        let mut buf = self.buffer();
+        let result = self.ping(&mut buf).await;
+
+        // the buffer is allocated for each request so it fits the grown payload
+        if let Some((step, max)) = self.grow {
+            let size = (self.payload_size() + step).min(max);
+            self.set_payload_size(size);
+        }
+
+        result
+    }
+
+    pub fn socket(&self) -> &S {
+        &self.sock
+    }
+
+    pub fn socket_mut(&mut self) -> &mut S {
+        &mut self.sock
+    }
+
+    /// Replaces the socket by a newly created one.
+    ///
+    /// It lets to recover after errors which leave the socket in a bad state,
+    /// the new socket has the options the original one was created with.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        match self.opener.as_ref() {
+            Some(open) => {
+                self.sock = open()?;
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "unknown socket options",
+            )),
+        }
+    }
+
+    /// Returns the size of the data sent in each request.
+    pub fn payload_size(&self) -> usize {
+        self.req.payload.as_ref().map_or(0, |p| p.len())
+    }
+
+    /// Replaces the payload of the next requests by a random one of the size.
+    pub fn set_payload_size(&mut self, size: usize) {
+        self.req.payload = Some(uniq_payload(size));
+    }
+
+    /// Burst sends `n` requests back to back and only then waits for the replies.
+    ///
+    /// The replies are matched to the requests by sequence number
+    /// so they may arrive in any order.
+    pub async fn burst(&mut self, n: usize) -> Vec<Result<PacketInfo>> {
+        self.timed_out.clear();
+        let mut results = Vec::with_capacity(n);
+        let mut pending = HashMap::new();
+        for _ in 0..n {
+            self.next_request();
+
+            let mut buf = self.buffer();
+            match self.send(&mut buf).await {
+                Ok(()) => {
+                    pending.insert(self.req.seq, time::Instant::now());
+                }
+                Err(err) => results.push(Err(err)),
+            }
+        }
+
+        let mut buf = self.buffer();
+        let deadline = time::Instant::now() + self.timeout;
+        while !pending.is_empty() {
+            let left = deadline.saturating_duration_since(time::Instant::now());
+            match timeout(left, self.receive(&mut buf)).await {
+                Some(Ok((mut info, seq, at))) => {
+                    if let Some(sent) = pending.remove(&seq) {
+                        info.time = at - sent;
+                        self.account(&info);
+                        results.push(Ok(info));
+                    }
+                }
+                Some(Err(err)) => {
+                    results.push(Err(err));
+                    break;
+                }
+                None => {
+                    let mut lost = pending.keys().copied().collect::<Vec<_>>();
+                    lost.sort();
+                    results.extend(lost.into_iter().map(|seq| Err(PingError::Timeout(seq))));
+                    self.timed_out.extend(pending.drain());
+                }
+            }
+        }
+
+        results
+    }
+
+    /// Broadcast sends a request and collects replies from all hosts
+    /// which respond to it until the timeout expires.
+    ///
+    /// Only the first reply from each host is returned.
+    pub async fn broadcast(&mut self) -> Vec<Result<PacketInfo>> {
+        self.next_request();
+
+        let mut buf = self.buffer();
+        if let Err(err) = self.send(&mut buf).await {
+            return vec![Err(err)];
+        }
+
+        let mut results = Vec::new();
+        let mut responders = HashSet::new();
+        let sent = time::Instant::now();
+        let deadline = sent + self.timeout;
+        while time::Instant::now() < deadline {
+            let left = deadline.saturating_duration_since(time::Instant::now());
+            match timeout(left, self.receive(&mut buf)).await {
+                Some(Ok((mut info, seq, at))) => {
+                    if seq == self.req.seq && responders.insert(info.ip_source_ip) {
+                        info.time = at - sent;
+                        self.account(&info);
+                        results.push(Ok(info));
+                    }
+                }
+                Some(Err(err)) => {
+                    results.push(Err(err));
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        if results.is_empty() {
+            results.push(Err(PingError::Timeout(self.req.seq)));
+        }
+
+        results
+    }
+
+    /// Waits for the replies to the requests of the last call
+    /// which haven't arrived in time.
+    ///
+    /// It lets to account replies which are late a bit
+    /// before the end of a session.
+    pub async fn wait_late(&mut self, grace: Duration) -> Vec<Result<PacketInfo>> {
+        let mut results = Vec::new();
+        let mut buf = self.buffer();
+        let deadline = time::Instant::now() + grace;
+        while !self.timed_out.is_empty() && time::Instant::now() < deadline {
+            let left = deadline.saturating_duration_since(time::Instant::now());
+            match timeout(left, self.receive(&mut buf)).await {
+                Some(Ok((mut info, seq, at))) => {
+                    if let Some(sent) = self.timed_out.remove(&seq) {
+                        info.time = at - sent;
+                        self.account(&info);
+                        results.push(Ok(info));
+                    }
+                }
+                Some(Err(err)) => {
+                    results.push(Err(err));
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        results
+    }
+
+    /// Replay queues packets which are sent by the next calls of [`run`]
+    /// instead of the echo requests.
+    ///
+    /// The replies are matched against the ident and sequence number of the replayed packet
+    /// as its payload may be anyone's.
+    ///
+    /// [`run`]: struct.Ping.html#method.run
+    pub fn replay<I: IntoIterator<Item = IcmpBuilder>>(&mut self, packets: I) {
+        self.replay.extend(packets)
+    }
+
+    fn next_request(&mut self) {
+        match self.replay.pop_front() {
+            Some(req) => {
+                self.req = req;
+                self.replayed = true;
+            }
+            None => self.req.seq = self.req.seq.wrapping_add(1),
+        }
+    }
+
+    /// Sets the sequence number the next request is sent with.
+    fn start_from(&mut self, seq: u16) {
+        // the number is incremented before each request
+        self.req.seq = seq.wrapping_sub(1);
+    }
+
+    /// Returns the number of leading payload bytes taken by the timestamp.
+    fn timestamp_size(&self) -> usize {
+        match self.req.payload.as_ref() {
+            Some(payload) if self.timestamp_payload && payload.len() >= TIMESTAMP_SIZE => {
+                TIMESTAMP_SIZE
+            }
+            _ => 0,
+        }
+    }
+
+    /// Returns the number of replies discarded because they exceed the maximum size.
+    pub fn oversized(&self) -> usize {
+        self.oversized
+    }
+
+    /// Returns the statistics gathered so far.
+    ///
+    /// It's cheap to call at any time as the statistics are kept in constant memory.
+    pub fn statistics(&self) -> Summary {
+        Summary {
+            sent: self.sent,
+            received: self.received,
+            min: self.rtt.min(),
+            avg: self.rtt.avg(),
+            max: self.rtt.max(),
+        }
+    }
+
+    /// Counts the reply to the statistics once its rtt is known.
+    fn account(&mut self, info: &PacketInfo) {
+        if PacketType::new(info.icmp_type).map_or(false, |tp| tp.is_reply()) {
+            self.received += 1;
+            self.rtt.push(info.time);
+        }
+    }
+
+    fn buffer(&self) -> Vec<u8> {
+        // the reply is usually an echo of the request wrapped in an ip header,
+        // a byte over the maximum lets to tell an oversized reply from a fitting one
+        let reply = match self.max_reply {
+            Some(size) => size + 1,
+            None => DEFAULT_BUFFER_SIZE.max(MAXIMUM_IP_HEADER_SIZE + self.req.hint_size()),
+        };
+
+        vec![0; reply.max(self.req.hint_size())]
+    }
+
+    /// Sends the request and waits for a reply.
+    ///
+    /// The request is resent if the reply doesn't arrive in time
+    /// and there are retries left, the timeout is split equally between attempts.
+    async fn ping(&mut self, buf: &mut [u8]) -> Result<PacketInfo> {
+        let wait = self.timeout / (self.retries as u32 + 1);
+        let mut now = time::Instant::now();
+        for retries in 0..=self.retries {
+            self.send(buf).await?;
+
+            now = time::Instant::now();
+            if let Some(reply) = timeout(wait, self.receive_reply(buf)).await {
+                let (mut info, at) = reply?;
+                info.time = at - now;
+                info.retries = retries;
+                self.account(&info);
+
+                return Ok(info);
+            }
+        }
 
-        self.ping(&mut buf).await
+        self.timed_out.insert(self.req.seq, now);
+        Err(PingError::Timeout(self.req.seq))
     }
 
-    async fn ping(&mut self, mut buf: &mut [u8]) -> Result<PacketInfo> {
+    async fn send(&mut self, mut buf: &mut [u8]) -> Result<()> {
+        if let Some(limiter) = self.limiter.as_mut() {
+            let wait = limiter.take();
+            if wait > Duration::default() {
+                smol::Timer::after(wait).await;
+            }
+        }
+
+        if self.timestamp_size() > 0 {
+            let stamp = self.epoch.elapsed().as_micros() as u64;
+            let payload = self.req.payload.as_mut().unwrap();
+            payload[..TIMESTAMP_SIZE].copy_from_slice(&stamp.to_be_bytes());
+        }
+
         let size = self.req.build(&mut buf).unwrap();
-        self.sock
-            .send(&buf[..size])
-            .await
-            .map_err(|err| PingError::Send(err))?;
 
-        let now = time::Instant::now();
+        let mut retries = 0;
+        loop {
+            match self.sock.send(&buf[..size]).await {
+                Ok(..) => break,
+                Err(ref err) if retries < SEND_RETRIES && is_transient(err) => {
+                    retries += 1;
+                    smol::Timer::after(SEND_BACKOFF * retries).await;
+                }
+                Err(err) => return Err(PingError::Send(err)),
+            }
+        }
+
+        self.sent += 1;
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.sent(&buf[..size]).map_err(PingError::Record)?;
+        }
+
+        Ok(())
+    }
+
+    /// Receives the reply to the current request.
+    ///
+    /// Replies matched by the identifier alone may answer an earlier request
+    /// so they're skipped unless they carry the sequence number of the current one.
+    async fn receive_reply(&mut self, buf: &mut [u8]) -> Result<(PacketInfo, time::Instant)> {
+        loop {
+            let (info, seq, at) = self.receive(buf).await?;
+            if !self.match_ident || seq == self.req.seq {
+                break Ok((info, at));
+            }
+        }
+    }
+
+    /// Receives the next packet which is sent in response to our requests.
+    ///
+    /// Returns the information about the packet without the round trip time,
+    /// the sequence number of the request the packet responds to,
+    /// and the time the packet was received.
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<(PacketInfo, u16, time::Instant)> {
         loop {
             let received_bytes = self
                 .sock
-                .recv(&mut buf)
+                .recv(buf)
                 .await
                 .map_err(|err| PingError::Recv(err))?;
 
-            let time = now.elapsed();
+            // there's nothing to parse in an empty datagram
+            if received_bytes == 0 {
+                continue;
+            }
+
+            let at = time::Instant::now();
+            if self.max_reply.map_or(false, |size| received_bytes > size) {
+                self.oversized += 1;
+                continue;
+            }
+
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder
+                    .received(&buf[..received_bytes])
+                    .map_err(PingError::Record)?;
+            }
+
             let ip = IPV4Packet::parse(&buf[..received_bytes]).unwrap();
+            // other protocols may leak into a raw socket on some configurations
+            if ip.protocol() != ip::Protocol::ICMP as u8 {
+                continue;
+            }
+
             let repl = IcmpPacket::parse(ip.payload().unwrap()).unwrap();
-            if own_packet(&self.req, &repl) {
-                break Ok(PacketInfo {
+            let timestamp_size = self.timestamp_size();
+            let own = match PacketType::new(repl.tp()) {
+                Some(tp) if self.replayed && tp.is_reply() => replies_to(&self.req, &repl),
+                _ => {
+                    own_packet(&self.req, &repl, timestamp_size, self.strict_rfc)
+                        || (self.match_ident && is_echo_reply_to(&self.req, &repl))
+                }
+            };
+            if own {
+                let payload_time = match PacketType::new(repl.tp()) {
+                    // a middlebox may strip the payload of a reply matched by the identifier
+                    Some(PacketType::EchoReply)
+                        if timestamp_size > 0 && repl.payload().len() >= TIMESTAMP_SIZE =>
+                    {
+                        let stamp = repl.payload()[..TIMESTAMP_SIZE].try_into().unwrap();
+                        let sent = Duration::from_micros(u64::from_be_bytes(stamp));
+                        (at - self.epoch).checked_sub(sent)
+                    }
+                    _ => None,
+                };
+
+                let info = PacketInfo {
                     ip_source_ip: std::net::IpAddr::from(ip.source_ip()),
+                    ip_destination_ip: std::net::IpAddr::from(ip.destination_ip()),
                     ip_ttl: ip.ttl(),
                     icmp_seq: repl.seq(),
+                    icmp_ident: repl.ident(),
                     icmp_type: repl.tp(),
+                    icmp_code: repl.code(),
                     received_bytes: received_bytes,
-                    time: time,
-                });
+                    icmp_payload_len: repl.payload().len(),
+                    time: Duration::default(),
+                    retries: 0,
+                    quoted: repl.quoted_packet().ok().map(|ip| QuotedPacket {
+                        destination: ip.destination_ip(),
+                        protocol: ip.protocol(),
+                    }),
+                    payload_time,
+                };
+
+                break Ok((info, request_seq(&repl), at));
+            }
+        }
+    }
+}
+
+impl Ping<Socket2> {
+    /// Sends the next requests to another address.
+    ///
+    /// The socket is reopened with the new address as well.
+    pub fn set_destination(&mut self, addr: net::IpAddr) {
+        let destination = unmap_address(addr);
+        let sock_addr = net::SocketAddr::new(destination, 0);
+        self.sock.1 = socket2::SockAddr::from(sock_addr);
+
+        if let Some(open) = self.opener.take() {
+            self.opener = Some(Box::new(move || {
+                let mut sock = open()?;
+                sock.1 = socket2::SockAddr::from(sock_addr);
+                Ok(sock)
+            }));
+        }
+
+        if let net::IpAddr::V4(destination) = destination {
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.destination = destination;
+            }
+        }
+    }
+}
+
+impl<S: Socket + AsMut<socket2::Socket>> Ping<S> {
+    /// Changes the TTL of the next requests.
+    ///
+    /// It allows to ramp the TTL between probes the way traceroute does.
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        self.sock.as_mut().set_ttl(ttl)?;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.ttl = ttl as u8;
+        }
+
+        Ok(())
+    }
+}
+
+/// TokenBucket limits the rate of requests.
+///
+/// The bucket holds a single token so requests are evenly spaced
+/// even when they're sent in a burst.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: time::Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: 1.0,
+            last: time::Instant::now(),
+        }
+    }
+
+    /// Takes a token and returns the time to wait before it's available.
+    fn take(&mut self) -> Duration {
+        let now = time::Instant::now();
+        let refill = (now - self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(1.0) - 1.0;
+        self.last = now;
+
+        if self.tokens >= 0.0 {
+            Duration::default()
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Resolves to None if the future isn't completed in the given time.
+async fn timeout<F: Future>(time: Duration, future: F) -> Option<F::Output> {
+    Timeout {
+        future: Box::pin(future),
+        timer: smol::Timer::after(time),
+    }
+    .await
+}
+
+struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    timer: smol::Timer,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+
+        match Pin::new(&mut self.timer).poll(cx) {
+            Poll::Ready(..) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Returns the sequence number of the request the packet responds to.
+///
+/// Error messages carry it in the quoted request.
+fn request_seq(repl: &IcmpPacket) -> u16 {
+    match PacketType::new(repl.tp()) {
+        Some(tp) if tp.is_error() => {
+            let ip = match IPV4Packet::parse(repl.payload()) {
+                Ok(ip) => ip,
+                Err(..) => return repl.seq(),
+            };
+
+            match ip.payload().map(IcmpPacket::parse) {
+                Some(Ok(icmp)) => icmp.seq(),
+                _ => repl.seq(),
             }
         }
+        _ => repl.seq(),
     }
 }
 
-fn own_packet(req: &IcmpBuilder, repl: &IcmpPacket) -> bool {
+/// Checks whether the packet responds to our requests.
+///
+/// The first bytes of the payload which change from request to request are skipped.
+/// If strict is set the payload quoted by a time exceeded message is verified in full.
+fn own_packet(req: &IcmpBuilder, repl: &IcmpPacket, skip: usize, strict: bool) -> bool {
+    let payload = req.payload.as_deref();
     match PacketType::new(repl.tp()) {
-        Some(PacketType::EchoReply) => req.payload.as_ref().unwrap().as_slice() == repl.payload(),
+        Some(PacketType::EchoReply) => {
+            payload.map_or(false, |payload| same_payload(payload, repl.payload(), skip))
+        }
         Some(PacketType::TimeExceeded) => {
             let ip = IPV4Packet::parse(repl.payload()).unwrap();
             let icmp = IcmpPacket::parse(ip.payload().unwrap()).unwrap();
@@ -124,23 +923,145 @@ fn own_packet(req: &IcmpBuilder, repl: &IcmpPacket) -> bool {
             //
             // rfc792  page 8
             // rfc1812 section 4.3.2.3
-            icmp.ident() == req.ident
+            //
+            // though if the payload is included we verify it too
+            // so pings with colliding identificators don't take each other's messages.
+            // the strict mode rejects such gateways to test them for compliance.
+            let payload = payload.unwrap_or(&[]);
+            let quoted = icmp.payload();
+            let same_payload = if quoted.len() < payload.len() {
+                !strict
+            } else {
+                same_payload(payload, &quoted[..payload.len()], skip)
+            };
+
+            icmp.ident() == req.ident && same_payload
         }
+        // most likely we ping localhost so we should skip our own request,
+        // requests of other pings on the host aren't responses either
         Some(PacketType::EchoRequest)
-            if req.payload.as_ref().unwrap().as_slice() == repl.payload()
-                && req.ident == repl.ident() =>
-        {
-            // req == replay
-            // most likely we ping localhost so we should skip our own request
-            false
-        }
+        | Some(PacketType::InformationRequest)
+        | Some(PacketType::Timestamp)
+        | Some(PacketType::ExtendedEchoRequest) => false,
+        // information messages have no payload so only the identificator can be verified
+        Some(PacketType::InformationReply) => req.ident == repl.ident(),
         _ => true, // unimplemented
     }
 }
 
-fn uniq_payload() -> Vec<u8> {
+/// Checks whether the packet is an echo reply with the identifier of the request.
+///
+/// Some middleboxes rewrite or strip the payload on the way back
+/// so it's the only thing left to verify, the sequence number is checked by the caller.
+fn is_echo_reply_to(req: &IcmpBuilder, repl: &IcmpPacket) -> bool {
+    repl.tp() == PacketType::EchoReply as u8 && repl.ident() == req.ident
+}
+
+/// Checks whether the packet is a reply with the identifier and the sequence number of the request.
+fn replies_to(req: &IcmpBuilder, repl: &IcmpPacket) -> bool {
+    let same_seq = match PacketType::new(repl.tp()) {
+        // the sequence number of an extended echo takes only the first byte of the field
+        Some(PacketType::ExtendedEchoReply) => repl.seq() >> 8 == req.seq >> 8,
+        _ => repl.seq() == req.seq,
+    };
+
+    same_seq && repl.ident() == req.ident
+}
+
+fn same_payload(payload: &[u8], echoed: &[u8], skip: usize) -> bool {
+    payload.len() == echoed.len() && payload.get(skip..) == echoed.get(skip..)
+}
+
+/// Recorder writes the exchanged packets into a pcap file.
+struct Recorder {
+    writer: pcap::Writer<fs::File>,
+    destination: net::Ipv4Addr,
+    ttl: u8,
+}
+
+impl Recorder {
+    fn new(file: fs::File, destination: net::Ipv4Addr, ttl: u8) -> io::Result<Self> {
+        Ok(Self {
+            writer: pcap::Writer::new(file)?,
+            destination,
+            ttl,
+        })
+    }
+
+    /// Records a request.
+    ///
+    /// The kernel builds the ip header of the request itself,
+    /// so we build a similar one to have a valid raw ip record.
+    /// The source is unspecified since we don't know which one the kernel picked.
+    fn sent(&mut self, icmp: &[u8]) -> io::Result<()> {
+        let mut buf = vec![0; 20 + icmp.len()];
+        let size = IPV4Builder::new(
+            self.ttl,
+            ip::Protocol::ICMP,
+            net::Ipv4Addr::UNSPECIFIED,
+            self.destination,
+            icmp,
+        )
+        .build(&mut buf)
+        .unwrap();
+
+        self.writer.write_packet(SystemTime::now(), &buf[..size])
+    }
+
+    fn received(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.writer.write_packet(SystemTime::now(), packet)
+    }
+}
+
+/// Checks whether the send error is caused by a backpressure
+/// and the send can be retried.
+fn is_transient(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Checks whether the send failed because there's no network to the destination,
+/// e.g. the interface is down.
+pub fn is_network_down(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENETDOWN) | Some(libc::ENETUNREACH)
+    )
+}
+
+/// Returns the local address the kernel picks as a source
+/// of packets sent to the destination.
+///
+/// It connects a UDP socket which makes the kernel to choose a route
+/// but doesn't send anything.
+pub fn source_address(destination: net::IpAddr) -> io::Result<net::IpAddr> {
+    let bind: net::IpAddr = match destination {
+        net::IpAddr::V4(..) => net::Ipv4Addr::UNSPECIFIED.into(),
+        net::IpAddr::V6(..) => net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    let sock = net::UdpSocket::bind((bind, 0))?;
+    // the port doesn't matter since nothing is sent
+    sock.connect((destination, 1))?;
+    sock.local_addr().map(|addr| addr.ip())
+}
+
+/// Converts an IPv4-mapped IPv6 address (::ffff:a.b.c.d) to the IPv4 one
+/// since the traffic to it is IPv4 and should go through an IPv4 socket.
+///
+/// rfc4291 section 2.5.5.2
+pub fn unmap_address(addr: net::IpAddr) -> net::IpAddr {
+    match addr {
+        net::IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => v6.to_ipv4().map_or(addr, net::IpAddr::V4),
+            _ => addr,
+        },
+        addr => addr,
+    }
+}
+
+fn uniq_payload(size: usize) -> Vec<u8> {
     let mut p = Vec::new();
-    for _ in 0..DATA_SIZE {
+    for _ in 0..size {
         p.push(rand::random())
     }
     p
@@ -159,11 +1080,20 @@ pub trait Socket {
 pub struct Socket2(smol::Async<socket2::Socket>, socket2::SockAddr);
 
 impl Socket2 {
-    fn new(sock: socket2::Socket, addr: net::SocketAddr) -> Self {
-        Self(
-            smol::Async::new(sock).unwrap(),
-            socket2::SockAddr::from(addr),
-        )
+    fn new(sock: socket2::Socket, addr: net::SocketAddr) -> io::Result<Self> {
+        Ok(Self(smol::Async::new(sock)?, socket2::SockAddr::from(addr)))
+    }
+}
+
+impl AsRef<socket2::Socket> for Socket2 {
+    fn as_ref(&self) -> &socket2::Socket {
+        self.0.get_ref()
+    }
+}
+
+impl AsMut<socket2::Socket> for Socket2 {
+    fn as_mut(&mut self) -> &mut socket2::Socket {
+        self.0.get_mut()
     }
 }
 
@@ -181,7 +1111,7 @@ impl Socket for Socket2 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::packet::ip::{self, IPV4Builder};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
     use std::{
         cell::RefCell,
         collections::HashMap,
@@ -194,6 +1124,13 @@ mod tests {
         recv_errors: HashMap<usize, io::Error>,
         send_errors: HashMap<usize, io::Error>,
         changer: HashMap<usize, Box<fn(&mut IcmpBuilder)>>,
+        delays: HashMap<usize, Duration>,
+        drops: HashSet<usize>,
+        empty: HashSet<usize>,
+        foreign: HashSet<usize>,
+        drop_probability: f64,
+        rng: Option<StdRng>,
+        ttl: u8,
         recv: usize,
         send: AtomicUsize,
     }
@@ -202,25 +1139,51 @@ mod tests {
     impl Socket for TestSocket {
         async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             self.recv += 1;
-            match self.recv_errors.get(&self.recv) {
-                Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
-                None => {
-                    if let Some(callback) = self.changer.get(&self.recv) {
-                        callback(self.builder.lock().as_mut().unwrap());
+            if let Some(delay) = self.delays.get(&self.recv) {
+                smol::Timer::after(*delay).await;
+            }
+
+            let probability = self.drop_probability;
+            let dropped = match self.rng.as_mut() {
+                Some(rng) => rng.gen_bool(probability),
+                None => false,
+            };
+            if dropped || self.drops.contains(&self.recv) {
+                // the reply never arrives so a timeout is the only way out
+                smol::Timer::after(Duration::from_secs(60)).await;
+            }
+
+            if self.empty.contains(&self.recv) {
+                return Ok(0);
+            }
+
+            match self.recv_errors.get(&self.recv) {
+                Some(err) => Err(io::Error::new(err.kind(), err.to_string())),
+                None => {
+                    if let Some(callback) = self.changer.get(&self.recv) {
+                        callback(self.builder.lock().as_mut().unwrap());
                     }
 
-                    let mut icmp = [0; 300];
+                    let mut icmp = [0; 1500];
                     let icmp_size = self.builder.lock().as_mut().unwrap().build(&mut icmp).unwrap();
+                    let protocol = if self.foreign.contains(&self.recv) {
+                        ip::Protocol::IP
+                    } else {
+                        ip::Protocol::ICMP
+                    };
                     let ip = IPV4Builder::new(
-                        0,
-                        ip::Protocol::ICMP,
+                        self.ttl,
+                        protocol,
                         net::Ipv4Addr::LOCALHOST,
                         net::Ipv4Addr::LOCALHOST,
                         &icmp[..icmp_size],
                     );
-                    let send_size = ip.build(buf).unwrap();
+                    // a real socket truncates a datagram which doesn't fit the buffer
+                    let mut datagram = [0; 1500];
+                    let size = ip.build(&mut datagram).unwrap().min(buf.len());
+                    buf[..size].copy_from_slice(&datagram[..size]);
 
-                    Ok(send_size)
+                    Ok(size)
                 }
             }
         }
@@ -237,11 +1200,93 @@ mod tests {
         }
     }
 
+    /// VirtualHost builds a test socket
+    /// which models how a remote host answers our requests.
+    struct VirtualHost {
+        reply_type: PacketType,
+        sock: TestSocket,
+    }
+
+    impl VirtualHost {
+        /// Creates a host which answers each request with an echo reply.
+        fn new() -> Self {
+            Self {
+                reply_type: PacketType::EchoReply,
+                sock: TestSocket {
+                    ttl: 64,
+                    ..TestSocket::default()
+                },
+            }
+        }
+
+        fn reply_type(mut self, tp: PacketType) -> Self {
+            self.reply_type = tp;
+            self
+        }
+
+        fn ttl(mut self, ttl: u8) -> Self {
+            self.sock.ttl = ttl;
+            self
+        }
+
+        /// Drops replies at random with the given probability.
+        /// The random sequence is seeded so the test is repeatable.
+        fn drop_probability(mut self, probability: f64) -> Self {
+            self.sock.drop_probability = probability;
+            self.sock.rng = Some(StdRng::seed_from_u64(0));
+            self
+        }
+
+        /// The n-th reply never arrives.
+        fn drop_reply(mut self, n: usize) -> Self {
+            self.sock.drops.insert(n);
+            self
+        }
+
+        /// The n-th read returns no data.
+        fn empty_reply(mut self, n: usize) -> Self {
+            self.sock.empty.insert(n);
+            self
+        }
+
+        /// The n-th read returns a datagram of another protocol than ICMP.
+        fn foreign_protocol(mut self, n: usize) -> Self {
+            self.sock.foreign.insert(n);
+            self
+        }
+
+        /// The n-th reply arrives after the delay.
+        fn delay(mut self, n: usize, delay: Duration) -> Self {
+            self.sock.delays.insert(n, delay);
+            self
+        }
+
+        fn send_error(mut self, n: usize, kind: io::ErrorKind) -> Self {
+            self.sock.send_errors.insert(n, kind.into());
+            self
+        }
+
+        fn recv_error(mut self, n: usize, kind: io::ErrorKind) -> Self {
+            self.sock.recv_errors.insert(n, kind.into());
+            self
+        }
+
+        /// Changes the n-th reply and the ones after it.
+        fn change(mut self, n: usize, changer: fn(&mut IcmpBuilder)) -> Self {
+            self.sock.changer.insert(n, Box::new(changer));
+            self
+        }
+
+        fn ping(self) -> Ping<TestSocket> {
+            let mut ping = Ping::new(self.sock);
+            *ping.sock.builder.get_mut().unwrap() =
+                ping.req.clone().with_type(self.reply_type as u8);
+            ping
+        }
+    }
+
     fn test_ping() -> Ping<TestSocket> {
-        let mut ping = Ping::new(TestSocket::default());
-        *ping.sock.builder.get_mut().unwrap() = ping.req.clone();
-        ping.sock.builder.get_mut().unwrap().tp = icmp::PacketType::EchoReply as u8;
-        ping
+        VirtualHost::new().ping()
     }
 
     fn counts(ping: &Ping<TestSocket>) -> (usize, usize) {
@@ -267,12 +1312,361 @@ mod tests {
     }
 
     #[test]
-    pub fn ping_send_error() {
+    pub fn ping_nonstandard_code() {
+        let mut ping = test_ping();
+        ping.req.code = 1;
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_seq, 1);
+    }
+
+    #[test]
+    pub fn ping_start_seq() {
+        let mut ping = test_ping();
+        ping.start_from(100);
+        ping.sock.builder.get_mut().unwrap().seq = ping.req.seq;
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_seq, 100);
+    }
+
+    #[test]
+    pub fn ping_late_reply() {
+        let mut ping = VirtualHost::new()
+            .delay(1, Duration::from_millis(50))
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+
+        let packets = smol::block_on(ping.wait_late(Duration::from_millis(100)));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].as_ref().unwrap().icmp_seq, 1);
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 1);
+        assert_eq!(recv, 2);
+    }
+
+    #[test]
+    pub fn ping_reopen() {
+        let mut ping = VirtualHost::new()
+            .send_error(1, io::ErrorKind::Other)
+            .ping();
+        assert!(ping.reopen().is_err());
+
+        let builder = ping.sock.builder.get_mut().unwrap().clone();
+        ping.opener = Some(Box::new(move || {
+            Ok(TestSocket {
+                builder: Mutex::new(builder.clone()),
+                ..TestSocket::default()
+            })
+        }));
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_err());
+
+        assert!(ping.reopen().is_ok());
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(counts(&ping), (1, 1));
+    }
+
+    #[test]
+    pub fn ping_ttl() {
+        let mut ping = VirtualHost::new().ttl(3).ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().ip_ttl, 3);
+    }
+
+    #[test]
+    pub fn ping_lossy_host() {
+        let mut ping = VirtualHost::new().drop_probability(1.0).ping();
+        ping.timeout = Duration::from_millis(10);
+
+        for seq in 1..=2 {
+            let packet = smol::block_on(ping.run());
+            assert!(matches!(packet, Err(PingError::Timeout(s)) if s == seq));
+        }
+    }
+
+    #[test]
+    pub fn ping_timestamp_payload() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| {
+                builder.payload.as_mut().unwrap()[..TIMESTAMP_SIZE].copy_from_slice(&[0; 8]);
+            })
+            .ping();
+        ping.timestamp_payload = true;
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert!(packet.unwrap().payload_time.is_some());
+    }
+
+    #[test]
+    pub fn ping_payload_len() {
+        let mut ping = test_ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_payload_len, DATA_SIZE);
+    }
+
+    #[test]
+    pub fn ping_information_request() {
+        let mut ping = VirtualHost::new()
+            .reply_type(PacketType::InformationReply)
+            .ping();
+        ping.req = icmp::InformationRequest::new(ping.req.ident, 0);
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        let packet = packet.unwrap();
+        assert_eq!(packet.icmp_type, PacketType::InformationReply as u8);
+        assert_eq!(packet.icmp_code, 0);
+        assert_eq!(packet.icmp_seq, 1);
+    }
+
+    #[test]
+    pub fn ping_destination() {
+        let mut ping = test_ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().ip_destination_ip, net::Ipv4Addr::LOCALHOST);
+    }
+
+    #[test]
+    pub fn ping_payload_size() {
+        let mut ping = test_ping();
+        ping.set_payload_size(100);
+        ping.sock.builder.get_mut().unwrap().payload = ping.req.payload.clone();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_payload_len, 100);
+        assert_eq!(ping.payload_size(), 100);
+    }
+
+    #[test]
+    pub fn ping_oversized_reply() {
+        let mut ping = VirtualHost::new().drop_reply(2).ping();
+        ping.timeout = Duration::from_millis(10);
+        // an echo reply is 20 + 8 + 32 bytes
+        ping.max_reply = Some(50);
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+        assert_eq!(ping.oversized(), 1);
+    }
+
+    #[test]
+    pub fn ping_statistics() {
+        let mut ping = VirtualHost::new().drop_reply(2).ping();
+        ping.timeout = Duration::from_millis(10);
+
+        assert_eq!(ping.statistics().sent, 0);
+        assert_eq!(ping.statistics().avg, None);
+
+        for _ in 0..3 {
+            let _ = smol::block_on(ping.run());
+        }
+
+        let summary = ping.statistics();
+        assert_eq!(summary.sent, 3);
+        assert_eq!(summary.received, 2);
+        assert!(summary.min <= summary.avg && summary.avg <= summary.max);
+        assert!(summary.min.is_some());
+    }
+
+    #[test]
+    pub fn ping_empty_reply() {
+        let mut ping = VirtualHost::new().empty_reply(1).ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Ok(packet) if packet.icmp_seq == 1));
+        assert_eq!(ping.sock.recv, 2);
+    }
+
+    #[test]
+    pub fn ping_grow() {
+        let mut ping = test_ping();
+        ping.grow = Some((8, DATA_SIZE + 16));
+
+        for size in &[DATA_SIZE, DATA_SIZE + 8, DATA_SIZE + 16, DATA_SIZE + 16] {
+            assert_eq!(ping.payload_size(), *size);
+            // the host echoes the payload of the current request
+            ping.sock.builder.get_mut().unwrap().payload = ping.req.payload.clone();
+            let packet = smol::block_on(ping.run());
+            assert!(matches!(packet, Ok(packet) if packet.icmp_payload_len == *size));
+        }
+    }
+
+    #[test]
+    pub fn ping_match_ident() {
+        let strip = |builder: &mut IcmpBuilder| builder.payload = None;
+
+        let mut ping = VirtualHost::new().change(1, strip).drop_reply(2).ping();
+        ping.timeout = Duration::from_millis(10);
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+
+        let mut ping = VirtualHost::new().change(1, strip).ping();
+        ping.match_ident = true;
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Ok(packet) if packet.icmp_seq == 1));
+
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| {
+                builder.payload = None;
+                builder.ident = builder.ident.wrapping_add(1);
+            })
+            .drop_reply(2)
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+        ping.match_ident = true;
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+
+        let mut ping = VirtualHost::new().change(1, strip).ping();
+        ping.match_ident = true;
+        ping.timestamp_payload = true;
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Ok(packet) if packet.payload_time.is_none()));
+    }
+
+    #[test]
+    pub fn ping_match_ident_stale_seq() {
+        // a late reply to the previous request arrives before the reply to the current one
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| {
+                builder.payload = None;
+                builder.seq = builder.seq.wrapping_sub(1);
+            })
+            .change(2, |builder| builder.seq = builder.seq.wrapping_add(1))
+            .ping();
+        ping.match_ident = true;
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Ok(packet) if packet.icmp_seq == 1));
+        assert_eq!(ping.sock.recv, 2);
+    }
+
+    #[test]
+    pub fn settings_builder() {
+        let addr = net::IpAddr::from([192, 0, 2, 1]);
+        let settings = SettingsBuilder::new(addr).build();
+        assert_eq!(settings.read_timeout, DEFAULT_TIMEOUT);
+        assert_eq!(settings.start_seq, 1);
+        assert_eq!(settings.ttl, None);
+
+        let settings = SettingsBuilder::new(addr)
+            .with_ttl(5)
+            .with_timeout(Duration::from_secs(1))
+            .with_payload_size(100)
+            .with_retries(2)
+            .build();
+        assert_eq!(settings.addr, addr);
+        assert_eq!(settings.ttl, Some(5));
+        assert_eq!(settings.read_timeout, Duration::from_secs(1));
+        assert_eq!(settings.payload.map(|p| p.len()), Some(100));
+        assert_eq!(settings.retries, 2);
+    }
+
+    #[test]
+    pub fn ping_own_request() {
+        // on localhost the socket reads our own request before the reply to it
+        for &match_ident in &[false, true] {
+            let mut ping = VirtualHost::new()
+                .change(1, |builder| builder.tp = PacketType::EchoRequest as u8)
+                .change(2, |builder| builder.tp = PacketType::EchoReply as u8)
+                .ping();
+            ping.match_ident = match_ident;
+
+            let packet = smol::block_on(ping.run());
+            let expected = PacketType::EchoReply as u8;
+            assert!(matches!(packet, Ok(packet) if packet.icmp_type == expected));
+            assert_eq!(ping.sock.recv, 2);
+        }
+
+        // a request of another ping on the host
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| {
+                builder.tp = PacketType::EchoRequest as u8;
+                builder.ident = builder.ident.wrapping_add(1);
+            })
+            .change(2, |builder| {
+                builder.tp = PacketType::EchoReply as u8;
+                builder.ident = builder.ident.wrapping_sub(1);
+            })
+            .ping();
+
+        let packet = smol::block_on(ping.run());
+        let expected = PacketType::EchoReply as u8;
+        assert!(matches!(packet, Ok(packet) if packet.icmp_type == expected));
+        assert_eq!(ping.sock.recv, 2);
+    }
+
+    #[test]
+    pub fn ping_foreign_protocol() {
+        let mut ping = VirtualHost::new().foreign_protocol(1).ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Ok(packet) if packet.icmp_seq == 1));
+        assert_eq!(ping.sock.recv, 2);
+    }
+
+    #[test]
+    pub fn ping_ident() {
+        let mut ping = test_ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_ident, ping.req.ident);
+    }
+
+    #[test]
+    pub fn ping_broadcast() {
         let mut ping = test_ping();
+        ping.timeout = Duration::from_millis(10);
+
+        let packets = smol::block_on(ping.broadcast());
+
+        // the test socket replies from the same address all the time
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].as_ref().unwrap().icmp_seq, 1);
+        assert!(counts(&ping).1 > 1);
+    }
+
+    #[test]
+    pub fn ping_events() {
+        let mut ping = VirtualHost::new()
+            .change(2, |builder| time_exceeded(builder, 0))
+            .send_error(3, io::ErrorKind::Other)
+            .ping();
 
-        ping.sock
-            .send_errors
-            .insert(2, io::ErrorKind::Other.into());
+        let event = PingEvent::from(smol::block_on(ping.run()));
+        assert!(matches!(event, PingEvent::Reply(info) if info.icmp_seq == 1));
+        let event = PingEvent::from(smol::block_on(ping.run()));
+        assert!(matches!(event, PingEvent::NonEcho(..)));
+        let event = PingEvent::from(smol::block_on(ping.run()));
+        assert!(matches!(event, PingEvent::SendError(..)));
+
+        let event = PingEvent::from(Err(PingError::Timeout(7)));
+        assert!(matches!(event, PingEvent::Timeout { seq: 7 }));
+    }
+
+    #[test]
+    pub fn ping_send_error() {
+        let mut ping = VirtualHost::new()
+            .send_error(2, io::ErrorKind::Other)
+            .ping();
 
         let packet = smol::block_on(ping.run());
         assert!(packet.is_ok());
@@ -292,11 +1686,9 @@ mod tests {
 
     #[test]
     pub fn ping_recv_error() {
-        let mut ping = test_ping();
-
-        ping.sock
-            .recv_errors
-            .insert(2, io::ErrorKind::Other.into());
+        let mut ping = VirtualHost::new()
+            .recv_error(2, io::ErrorKind::Other)
+            .ping();
 
         let packet = smol::block_on(ping.run());
         assert!(packet.is_ok());
@@ -316,22 +1708,15 @@ mod tests {
 
     #[test]
     pub fn ping_recv_unexpected_icmp_packet() {
-        let mut ping = test_ping();
-
         // spoil the playgound
-        ping.sock.changer.insert(
-            2,
-            Box::new(|builder| {
+        let mut ping = VirtualHost::new()
+            .change(2, |builder| {
                 builder.payload.as_mut().map(|p| p.reverse());
-            }),
-        );
-
-        ping.sock.changer.insert(
-            4,
-            Box::new(|builder| {
+            })
+            .change(4, |builder| {
                 builder.payload.as_mut().map(|p| p.reverse());
-            }),
-        );
+            })
+            .ping();
 
         let packet = smol::block_on(ping.run());
         assert!(packet.is_ok());
@@ -345,4 +1730,338 @@ mod tests {
         assert_eq!(send, 2);
         assert_eq!(recv, 4);
     }
+
+    /// Turns the reply into a time exceeded message
+    /// which quotes the request with the given part of the payload.
+    fn time_exceeded(builder: &mut IcmpBuilder, quoted_payload: usize) {
+        let request = builder.clone().with_type(PacketType::EchoRequest as u8);
+        let mut icmp = vec![0; request.hint_size()];
+        request.build(&mut icmp).unwrap();
+        icmp.truncate(8 + quoted_payload);
+
+        let mut quote = vec![0; 20 + icmp.len()];
+        IPV4Builder::new(
+            1,
+            ip::Protocol::ICMP,
+            net::Ipv4Addr::LOCALHOST,
+            net::Ipv4Addr::LOCALHOST,
+            &icmp,
+        )
+        .build(&mut quote)
+        .unwrap();
+
+        *builder = IcmpBuilder::new()
+            .with_type(PacketType::TimeExceeded as u8)
+            .with_payload(&quote);
+    }
+
+    #[test]
+    pub fn ping_time_exceeded_truncated_quote() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| time_exceeded(builder, 0))
+            .ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_type, PacketType::TimeExceeded as u8);
+    }
+
+    #[test]
+    pub fn ping_time_exceeded_full_quote() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| time_exceeded(builder, DATA_SIZE))
+            .ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_type, PacketType::TimeExceeded as u8);
+    }
+
+    #[test]
+    pub fn ping_time_exceeded_code() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| {
+                time_exceeded(builder, DATA_SIZE);
+                builder.code = 1;
+            })
+            .ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        let packet = packet.unwrap();
+        assert_eq!(packet.icmp_type, PacketType::TimeExceeded as u8);
+        assert_eq!(packet.icmp_code, 1);
+    }
+
+    #[test]
+    pub fn ping_time_exceeded_strict_rfc() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| time_exceeded(builder, 0))
+            .drop_reply(2)
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+        ping.strict_rfc = true;
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| time_exceeded(builder, DATA_SIZE))
+            .ping();
+        ping.strict_rfc = true;
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+    }
+
+    #[test]
+    pub fn ping_time_exceeded_foreign_payload() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| {
+                if let Some(payload) = builder.payload.as_mut() {
+                    payload.reverse();
+                }
+                time_exceeded(builder, DATA_SIZE);
+            })
+            .drop_reply(2)
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+    }
+
+    #[test]
+    pub fn ping_replay() {
+        let mut ping = test_ping();
+
+        let replayed = ping.req.clone().with_seq(100);
+        ping.replay(vec![replayed]);
+        ping.sock.changer.insert(1, Box::new(|builder| builder.seq = 100));
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(ping.req.seq, 100);
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(ping.req.seq, 101);
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 2);
+        assert_eq!(recv, 2);
+    }
+
+    #[test]
+    pub fn ping_replay_matches_ident_and_seq() {
+        // the first reply answers another request
+        let mut ping = VirtualHost::new()
+            .reply_type(PacketType::TimestampReply)
+            .change(2, |builder| builder.seq = 100)
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+
+        let replayed = IcmpBuilder::new()
+            .with_type(PacketType::Timestamp as u8)
+            .with_ident(ping.req.ident)
+            .with_seq(100);
+        ping.replay(vec![replayed.clone()]);
+
+        let packet = smol::block_on(ping.run()).unwrap();
+        assert_eq!(packet.icmp_seq, 100);
+        assert_eq!(packet.icmp_type, PacketType::TimestampReply as u8);
+        assert_eq!(counts(&ping), (1, 2));
+
+        // a reply with another ident isn't ours
+        let mut ping = VirtualHost::new()
+            .reply_type(PacketType::TimestampReply)
+            .change(1, |builder| {
+                builder.seq = 100;
+                builder.ident = builder.ident.wrapping_add(1);
+            })
+            .drop_reply(2)
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+        ping.replay(vec![replayed.with_ident(ping.req.ident)]);
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(100))));
+    }
+
+    #[test]
+    pub fn ping_burst() {
+        // the replies are reordered
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| builder.seq = 3)
+            .change(2, |builder| builder.seq = 1)
+            .change(3, |builder| builder.seq = 2)
+            .ping();
+
+        let packets = smol::block_on(ping.burst(3));
+        let seqs = packets
+            .into_iter()
+            .map(|packet| packet.unwrap().icmp_seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, vec![3, 1, 2]);
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 3);
+        assert_eq!(recv, 3);
+    }
+
+    #[test]
+    pub fn ping_rate() {
+        let mut ping = test_ping();
+        ping.limiter = Some(TokenBucket::new(100.0));
+
+        let start = time::Instant::now();
+        for _ in 0..3 {
+            let packet = smol::block_on(ping.run());
+            assert!(packet.is_ok());
+        }
+        // the first request is sent at once and the others 10ms apart
+        assert!(start.elapsed() >= Duration::from_millis(15));
+    }
+
+    #[test]
+    pub fn ping_burst_send_error() {
+        let mut ping = VirtualHost::new()
+            .send_error(2, io::ErrorKind::Other)
+            .change(1, |builder| builder.seq = 1)
+            .change(2, |builder| builder.seq = 3)
+            .ping();
+
+        let packets = smol::block_on(ping.burst(3));
+        assert_eq!(packets.len(), 3);
+        assert!(packets[0].is_err());
+        assert_eq!(packets[1].as_ref().unwrap().icmp_seq, 1);
+        assert_eq!(packets[2].as_ref().unwrap().icmp_seq, 3);
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 3);
+        assert_eq!(recv, 2);
+    }
+
+    #[test]
+    pub fn source_address_of_localhost() {
+        let addr = source_address(net::Ipv4Addr::LOCALHOST.into());
+
+        assert!(addr.is_ok());
+        assert_eq!(addr.unwrap(), net::Ipv4Addr::LOCALHOST);
+    }
+
+    #[test]
+    pub fn unmap_ipv4_mapped_address() {
+        let mapped: net::IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        let localhost: net::IpAddr = net::Ipv6Addr::LOCALHOST.into();
+
+        assert_eq!(unmap_address(mapped), net::Ipv4Addr::new(192, 0, 2, 1));
+        assert_eq!(unmap_address(localhost), localhost);
+    }
+
+    #[test]
+    pub fn ipv4_mapped_target_picks_ipv4_socket() {
+        let mapped: net::IpAddr = "::ffff:127.0.0.1".parse().unwrap();
+        let mut ping = match SettingsBuilder::new(mapped).build().build() {
+            Ok(ping) => ping,
+            // a raw socket can't be opened without privileges
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("socket: {}", err),
+        };
+
+        let destination = net::SocketAddrV4::new(net::Ipv4Addr::LOCALHOST, 0);
+        assert_eq!(ping.sock.1.as_inet(), Some(destination));
+        assert_eq!(ping.req.tp, PacketType::EchoRequest as u8);
+
+        ping.set_destination("::ffff:192.0.2.1".parse().unwrap());
+        let destination = net::SocketAddrV4::new(net::Ipv4Addr::new(192, 0, 2, 1), 0);
+        assert_eq!(ping.sock.1.as_inet(), Some(destination));
+    }
+
+    #[test]
+    pub fn ping_send_would_block() {
+        let mut ping = VirtualHost::new()
+            .send_error(1, io::ErrorKind::WouldBlock)
+            .ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().icmp_seq, 1);
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 2);
+        assert_eq!(recv, 1);
+    }
+
+    #[test]
+    pub fn ping_send_would_block_too_long() {
+        let mut host = VirtualHost::new();
+        for i in 1..=4 {
+            host = host.send_error(i, io::ErrorKind::WouldBlock);
+        }
+        let mut ping = host.ping();
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_err());
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 4);
+        assert_eq!(recv, 0);
+    }
+
+    #[test]
+    pub fn ping_timeout() {
+        let mut ping = VirtualHost::new().drop_reply(1).ping();
+        ping.timeout = Duration::from_millis(10);
+
+        let packet = smol::block_on(ping.run());
+        assert!(matches!(packet, Err(PingError::Timeout(1))));
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 2);
+        assert_eq!(recv, 2);
+    }
+
+    #[test]
+    pub fn ping_retries() {
+        let mut ping = VirtualHost::new().drop_reply(1).drop_reply(2).ping();
+        ping.timeout = Duration::from_millis(20);
+        ping.retries = 2;
+
+        let packet = smol::block_on(ping.run());
+        assert!(packet.is_ok());
+        assert_eq!(packet.unwrap().retries, 2);
+        assert_eq!(ping.req.seq, 1);
+
+        let (send, recv) = counts(&ping);
+        assert_eq!(send, 3);
+        assert_eq!(recv, 3);
+    }
+
+    #[test]
+    pub fn ping_burst_timeout() {
+        let mut ping = VirtualHost::new()
+            .change(1, |builder| builder.seq = 2)
+            .drop_reply(2)
+            .ping();
+        ping.timeout = Duration::from_millis(10);
+
+        let packets = smol::block_on(ping.burst(3));
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].as_ref().unwrap().icmp_seq, 2);
+        assert!(matches!(packets[1], Err(PingError::Timeout(1))));
+        assert!(matches!(packets[2], Err(PingError::Timeout(3))));
+    }
+
+    #[test]
+    pub fn network_down_errors() {
+        let error = io::Error::from_raw_os_error;
+        assert!(is_network_down(&error(libc::ENETDOWN)));
+        assert!(is_network_down(&error(libc::ENETUNREACH)));
+        assert!(!is_network_down(&error(libc::ENOBUFS)));
+        assert!(!is_network_down(&io::ErrorKind::TimedOut.into()));
+    }
 }
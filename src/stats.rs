@@ -0,0 +1,207 @@
+//! This module contains the statistics of round trip times.
+//!
+//! The statistics are kept in constant memory
+//! so a ping which runs for days doesn't grow.
+
+use std::time::Duration;
+
+/// A snapshot of the statistics of a ping.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    /// The number of sent requests including resent ones.
+    pub sent: usize,
+    /// The number of replies to the requests.
+    pub received: usize,
+    pub min: Option<Duration>,
+    pub avg: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct RttStats {
+    count: usize,
+    min: Duration,
+    max: Duration,
+    // in nanoseconds so neither a long run overflows it nor the average loses precision
+    sum: u128,
+    // the running mean and the sum of squared differences from it
+    // in seconds by Welford's algorithm
+    mean: f64,
+    m2: f64,
+    reservoir: Vec<Duration>,
+    reservoir_size: usize,
+}
+
+impl RttStats {
+    /// Creates statistics which keep a random sample of the given size
+    /// to estimate percentiles.
+    pub fn with_reservoir(size: usize) -> Self {
+        Self {
+            reservoir: Vec::with_capacity(size),
+            reservoir_size: size,
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, rtt: Duration) {
+        self.count += 1;
+        if self.count == 1 || rtt < self.min {
+            self.min = rtt;
+        }
+        if rtt > self.max {
+            self.max = rtt;
+        }
+        self.sum = self.sum.saturating_add(rtt.as_nanos());
+
+        let x = rtt.as_secs_f64();
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+
+        // each sample ends up in the reservoir with the same probability
+        if self.reservoir.len() < self.reservoir_size {
+            self.reservoir.push(rtt);
+        } else if self.reservoir_size > 0 {
+            let i = rand::random::<usize>() % self.count;
+            if i < self.reservoir_size {
+                self.reservoir[i] = rtt;
+            }
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.value(self.min)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.value(self.max)
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        let avg = self.sum / self.count.max(1) as u128;
+        self.value(Duration::from_nanos(avg.min(u64::MAX as u128) as u64))
+    }
+
+    /// Returns the standard deviation of rtt.
+    pub fn mdev(&self) -> Option<Duration> {
+        self.value(Duration::from_secs_f64(
+            (self.m2 / self.count.max(1) as f64).sqrt(),
+        ))
+    }
+
+    /// Estimates the percentile of rtt by the sample,
+    /// it's None if the statistics are created without a reservoir.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let sample = self.sorted_sample()?;
+        let i = ((sample.len() - 1) as f64 * p / 100.0).round() as usize;
+        sample.get(i).copied()
+    }
+
+    /// Estimates the average of rtt by the sample without the pct percent
+    /// of the smallest and the pct percent of the largest values,
+    /// it's None if the statistics are created without a reservoir.
+    pub fn trimmed_mean(&self, pct: f64) -> Option<Duration> {
+        let sample = self.sorted_sample()?;
+        let trimmed = (sample.len() as f64 * pct / 100.0).floor() as usize;
+        let kept = sample.get(trimmed..sample.len().saturating_sub(trimmed))?;
+        if kept.is_empty() {
+            return None;
+        }
+
+        let sum = kept.iter().map(|rtt| rtt.as_nanos()).sum::<u128>();
+        let avg = sum / kept.len() as u128;
+        Some(Duration::from_nanos(avg.min(u64::MAX as u128) as u64))
+    }
+
+    fn sorted_sample(&self) -> Option<Vec<Duration>> {
+        if self.reservoir.is_empty() {
+            return None;
+        }
+
+        let mut sample = self.reservoir.clone();
+        sample.sort();
+        Some(sample)
+    }
+
+    fn value(&self, value: Duration) -> Option<Duration> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let stats = RttStats::default();
+
+        assert!(stats.is_empty());
+        assert_eq!(stats.min(), None);
+        assert_eq!(stats.avg(), None);
+        assert_eq!(stats.mdev(), None);
+    }
+
+    #[test]
+    fn min_max_avg_mdev() {
+        let mut stats = RttStats::default();
+        for ms in &[2, 4, 4, 4, 5, 5, 7, 9] {
+            stats.push(Duration::from_millis(*ms));
+        }
+
+        assert_eq!(stats.count(), 8);
+        assert_eq!(stats.min(), Some(Duration::from_millis(2)));
+        assert_eq!(stats.max(), Some(Duration::from_millis(9)));
+        assert_eq!(stats.avg(), Some(Duration::from_millis(5)));
+        let mdev = stats.mdev().unwrap().as_secs_f64();
+        assert!((mdev - 0.002).abs() < 1e-9);
+        assert_eq!(stats.percentile(50.0), None);
+    }
+
+    #[test]
+    fn avg_of_small_durations() {
+        let mut stats = RttStats::default();
+        for i in 0..1_000_000 {
+            stats.push(Duration::from_nanos(1000 + i % 2 * 1000));
+        }
+
+        assert_eq!(stats.avg(), Some(Duration::from_nanos(1500)));
+    }
+
+    #[test]
+    fn trimmed_mean() {
+        let mut stats = RttStats::with_reservoir(100);
+        for ms in &[2, 4, 4, 4, 5, 5, 7, 9, 10, 2000] {
+            stats.push(Duration::from_millis(*ms));
+        }
+
+        assert_eq!(stats.trimmed_mean(0.0), stats.avg());
+        assert_eq!(stats.trimmed_mean(10.0), Some(Duration::from_millis(6)));
+        assert_eq!(stats.trimmed_mean(49.0), Some(Duration::from_millis(5)));
+        assert_eq!(RttStats::default().trimmed_mean(10.0), None);
+    }
+
+    #[test]
+    fn reservoir_is_bounded() {
+        let mut stats = RttStats::with_reservoir(10);
+        for ms in 1..=1000 {
+            stats.push(Duration::from_millis(ms));
+        }
+
+        assert_eq!(stats.reservoir.len(), 10);
+        assert_eq!(stats.count(), 1000);
+        assert!(stats.percentile(50.0).is_some());
+    }
+}
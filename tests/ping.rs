@@ -1,4 +1,13 @@
-use rexpect::{errors::*, process::wait::WaitStatus, spawn};
+use rexpect::{
+    errors::*,
+    process::{signal::Signal, wait::WaitStatus},
+    spawn,
+};
+use std::{
+    io::{self, BufRead, BufReader},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
 
 fn run(addr: &str, params: &[&str], packet_limit: usize) -> Result<usize> {
     let params = params.join(" ");
@@ -18,6 +27,46 @@ fn run(addr: &str, params: &[&str], packet_limit: usize) -> Result<usize> {
     Ok(count)
 }
 
+fn interrupt(addr: &str, params: &[&str], packets: usize) -> Result<(String, WaitStatus)> {
+    let params = params.join(" ");
+    let command = format!("./target/debug/niping {} {}", addr, params);
+    let mut p = spawn(&command, Some(30_000))?;
+    p.exp_regex("PING.*\n")?;
+
+    for _ in 0..packets {
+        p.exp_regex("\\n")?;
+    }
+
+    p.process.signal(Signal::SIGINT)?;
+    let (_, summary) = p.exp_regex("\\d+ packets transmitted, received \\d+.*")?;
+    let status = p.process.wait()?;
+
+    Ok((summary, status))
+}
+
+/// Runs niping with its stdout piped and returns the times its lines arrive.
+fn piped(addr: &str, params: &[&str], lines: usize) -> io::Result<Vec<Instant>> {
+    let mut p = Command::new("./target/debug/niping")
+        .arg(addr)
+        .args(params.iter().flat_map(|param| param.split_whitespace()))
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = BufReader::new(p.stdout.take().unwrap()).lines();
+    // the header is printed before the first request
+    stdout.next().transpose()?;
+
+    let mut times = Vec::new();
+    for line in stdout.take(lines) {
+        line?;
+        times.push(Instant::now());
+    }
+
+    p.kill()?;
+    p.wait()?;
+
+    Ok(times)
+}
+
 #[test]
 fn ping() {
     let limit = 5;
@@ -59,3 +108,25 @@ fn ping_option_count_dns() {
     assert!(packets.is_ok());
     assert_eq!(packets.unwrap(), count);
 }
+
+#[test]
+fn ping_interrupt_prints_summary() {
+    let result = interrupt("8.8.8.8", &[], 2);
+    assert!(result.is_ok());
+
+    let (summary, status) = result.unwrap();
+    assert!(summary.contains("packets transmitted"));
+    assert!(matches!(status, WaitStatus::Exited(_, 0)));
+}
+
+#[test]
+fn ping_piped_output_is_not_buffered() {
+    let interval = Duration::from_millis(500);
+    let times = piped("127.0.0.1", &["-c 4", "-i 0.5"], 4);
+    assert!(times.is_ok());
+
+    for lines in times.unwrap().windows(2) {
+        let gap = lines[1] - lines[0];
+        assert!(gap > interval / 2 && gap < interval * 2);
+    }
+}